@@ -0,0 +1,89 @@
+//! Compares searching a large file line by line, the way grep used to, with
+//! the whole-buffer search, once reading the file and once memory-mapping it.
+//!
+//! Run with `cargo bench -p grep --bench search`.
+
+use regex::Regex;
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const LINES: usize = 2_000_000;
+const RUNS: usize = 3;
+const PATTERN: &str = "needle [0-9]+";
+
+fn generate_file(path: &Path) {
+    if path.exists() {
+        return;
+    }
+    let mut file = std::io::BufWriter::new(File::create(path).unwrap());
+    for i in 0..LINES {
+        if i % 10_000 == 0 {
+            writeln!(file, "{} WARN found needle {} in the haystack", i, i).unwrap();
+        } else {
+            writeln!(file, "{} INFO nothing to see here, just some hay", i).unwrap();
+        }
+    }
+}
+
+fn best_of<F: FnMut() -> usize>(mut f: F) -> (Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut count = 0;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        count = f();
+        best = best.min(start.elapsed());
+    }
+    (best, count)
+}
+
+fn line_by_line(path: &Path) -> usize {
+    let re = Regex::new(PATTERN).unwrap();
+    let reader = BufReader::new(File::open(path).unwrap());
+    reader
+        .lines()
+        .filter(|line| re.is_match(line.as_ref().unwrap()))
+        .count()
+}
+
+fn grep(path: &Path, extra_args: &[&str]) -> usize {
+    let output = Command::new(env!("CARGO_BIN_EXE_grep"))
+        .args([PATTERN, "--color=never", "-f"])
+        .arg(path)
+        .args(extra_args)
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    output
+        .stdout
+        .split(|&b| b == b'\n')
+        .filter(|l| l.starts_with(b"line "))
+        .count()
+}
+
+fn main() {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("search-bench.log");
+    generate_file(&path);
+    let size = fs::metadata(&path).unwrap().len();
+    println!("searching {} MiB", size / 1024 / 1024);
+
+    let (baseline, expected) = best_of(|| line_by_line(&path));
+    println!("line by line:        {:?}", baseline);
+    for (name, args) in [
+        ("whole buffer, read:", &["--no-mmap"][..]),
+        ("whole buffer, mmap:", &[]),
+    ] {
+        let (elapsed, count) = best_of(|| grep(&path, args));
+        assert_eq!(count, expected);
+        println!(
+            "{} {:?} ({:.2}x)",
+            name,
+            elapsed,
+            baseline.as_secs_f64() / elapsed.as_secs_f64()
+        );
+    }
+}
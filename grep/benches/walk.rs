@@ -0,0 +1,72 @@
+//! Times a recursive search over a generated tree of 100k small files, once
+//! with a single walker thread and once with the default thread count.
+//!
+//! Run with `cargo bench -p grep --bench walk`.
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const TOP_DIRS: usize = 10;
+const SUB_DIRS: usize = 10;
+const FILES_PER_DIR: usize = 1000;
+const RUNS: usize = 3;
+/// Same as the default of `--n-thread`.
+const DEFAULT_THREADS: usize = 4;
+
+fn generate_tree(root: &Path) {
+    if root.join("done").exists() {
+        return;
+    }
+    for i in 0..TOP_DIRS {
+        for j in 0..SUB_DIRS {
+            let dir = root.join(format!("d{}", i)).join(format!("d{}", j));
+            fs::create_dir_all(&dir).unwrap();
+            for k in 0..FILES_PER_DIR {
+                let content = if k % 1000 == 0 {
+                    "fn main() {\n    println!(\"needle\");\n}\n"
+                } else {
+                    "fn main() {\n    println!(\"hay\");\n}\n"
+                };
+                fs::write(dir.join(format!("f{}.rs", k)), content).unwrap();
+            }
+        }
+    }
+    fs::write(root.join("done"), "").unwrap();
+}
+
+fn search(root: &Path, threads: usize) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            let status = Command::new(env!("CARGO_BIN_EXE_grep"))
+                .args(["needle", "-r", "-d"])
+                .arg(root)
+                .args(["--n-thread", &threads.to_string()])
+                .stdout(Stdio::null())
+                .status()
+                .unwrap();
+            assert!(status.success());
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let root = Path::new(env!("CARGO_TARGET_TMPDIR")).join("walk-bench");
+    generate_tree(&root);
+
+    let single = search(&root, 1);
+    let parallel = search(&root, DEFAULT_THREADS);
+    println!("walk 100k files, 1 thread:  {:?}", single);
+    println!(
+        "walk 100k files, {} threads: {:?}",
+        DEFAULT_THREADS, parallel
+    );
+    println!(
+        "speedup: {:.2}x",
+        single.as_secs_f64() / parallel.as_secs_f64()
+    );
+}
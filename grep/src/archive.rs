@@ -0,0 +1,205 @@
+use flate2::read::MultiGzDecoder;
+use xz2::read::XzDecoder;
+use zip::ZipArchive;
+
+use std::fs::File;
+use std::io::{self, Read, Result};
+use std::path::{Path, PathBuf};
+
+/// Single-stream compression formats which `-z` decompresses on the fly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_path(path: &Path) -> Option<Compression> {
+        let name = path.file_name()?.to_str()?;
+        if name.ends_with(".gz") || name.ends_with(".tgz") {
+            Some(Compression::Gzip)
+        } else if name.ends_with(".xz") || name.ends_with(".txz") {
+            Some(Compression::Xz)
+        } else if name.ends_with(".zst") || name.ends_with(".tzst") {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    pub fn decoder<'a, R: Read + 'a>(self, reader: R) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Compression::Xz => Box::new(XzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        })
+    }
+
+    pub fn decompress(self, file: File, file_size: u64) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(file_size as usize * 4);
+        self.decoder(file)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Archive formats whose members `--search-archives` searches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Archive {
+    Tar(Option<Compression>),
+    Zip,
+}
+
+impl Archive {
+    pub fn from_path(path: &Path) -> Option<Archive> {
+        let name = path.file_name()?.to_str()?;
+        if name.ends_with(".zip") {
+            return Some(Archive::Zip);
+        }
+        let is_tar = [
+            ".tar", ".tar.gz", ".tgz", ".tar.xz", ".txz", ".tar.zst", ".tzst",
+        ]
+        .iter()
+        .any(|ext| name.ends_with(ext));
+        if is_tar {
+            Some(Archive::Tar(Compression::from_path(path)))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the path a member is reported as, `archive:member`.
+    pub fn member_path(path: &Path, name: &str) -> PathBuf {
+        PathBuf::from(format!("{}:{}", path.display(), name))
+    }
+
+    /// Calls `f` with the name and content of every regular file in the
+    /// archive.
+    pub fn for_each_member<F>(self, file: File, mut f: F) -> Result<()>
+    where
+        F: FnMut(&str, &[u8]) -> Result<()>,
+    {
+        let mut content = Vec::new();
+        match self {
+            Archive::Tar(compression) => {
+                let reader: Box<dyn Read> = match compression {
+                    Some(compression) => compression.decoder(file)?,
+                    None => Box::new(file),
+                };
+                let mut archive = tar::Archive::new(reader);
+                for entry in archive.entries()? {
+                    let mut entry = entry?;
+                    if !entry.header().entry_type().is_file() {
+                        continue;
+                    }
+                    let name = entry.path()?.to_string_lossy().into_owned();
+                    content.clear();
+                    entry.read_to_end(&mut content)?;
+                    f(&name, &content)?;
+                }
+            }
+            Archive::Zip => {
+                let mut archive = ZipArchive::new(file).map_err(io::Error::other)?;
+                for i in 0..archive.len() {
+                    let mut member = archive.by_index(i).map_err(io::Error::other)?;
+                    if !member.is_file() {
+                        continue;
+                    }
+                    let name = member.name().to_string();
+                    content.clear();
+                    member.read_to_end(&mut content)?;
+                    f(&name, &content)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::archive::*;
+
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    use zip::write::{FileOptions, ZipWriter};
+
+    fn member_paths(path: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        let archive = Archive::from_path(path).unwrap();
+        let mut members = Vec::new();
+        archive
+            .for_each_member(File::open(path).unwrap(), |name, content| {
+                members.push((Archive::member_path(path, name), content.to_vec()));
+                Ok(())
+            })
+            .unwrap();
+        members
+    }
+
+    #[test]
+    fn detect_formats() {
+        let format = |name| Archive::from_path(Path::new(name));
+        assert_eq!(format("a.tar"), Some(Archive::Tar(None)));
+        assert_eq!(format("a.tgz"), Some(Archive::Tar(Some(Compression::Gzip))));
+        assert_eq!(
+            format("a.tar.zst"),
+            Some(Archive::Tar(Some(Compression::Zstd)))
+        );
+        assert_eq!(format("a.zip"), Some(Archive::Zip));
+        assert_eq!(format("a.gz"), None);
+        assert_eq!(
+            Compression::from_path(Path::new("a.log.xz")),
+            Some(Compression::Xz)
+        );
+        assert_eq!(Compression::from_path(Path::new("a.log")), None);
+    }
+
+    #[test]
+    fn read_tar_gz_members() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.tar.gz");
+        let encoder = GzEncoder::new(File::create(&path).unwrap(), Default::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, content) in [("src/a.txt", "hello\n"), ("b.txt", "world\n")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, content.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let member = |name: &str| PathBuf::from(format!("{}:{}", path.display(), name));
+        assert_eq!(
+            member_paths(&path),
+            [
+                (member("src/a.txt"), b"hello\n".to_vec()),
+                (member("b.txt"), b"world\n".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_zip_members() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.zip");
+        let mut writer = ZipWriter::new(File::create(&path).unwrap());
+        writer
+            .add_directory("src/", FileOptions::default())
+            .unwrap();
+        writer
+            .start_file("src/a.txt", FileOptions::default())
+            .unwrap();
+        writer.write_all(b"hello\n").unwrap();
+        writer.finish().unwrap();
+
+        let member = |name: &str| PathBuf::from(format!("{}:{}", path.display(), name));
+        assert_eq!(
+            member_paths(&path),
+            [(member("src/a.txt"), b"hello\n".to_vec())]
+        );
+    }
+}
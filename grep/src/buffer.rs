@@ -0,0 +1,431 @@
+use memchr::{memchr, memchr_iter, memrchr};
+use memmap2::Mmap;
+
+use crate::matcher::Matcher;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Result};
+use std::ops::Deref;
+
+/// Files at least this large are memory-mapped instead of read into memory.
+const MMAP_THRESHOLD: u64 = 1024 * 1024;
+
+/// The whole content of a file, either read into memory or memory-mapped.
+pub enum FileBuffer {
+    Heap(Vec<u8>),
+    Mmap(Mmap),
+}
+
+impl FileBuffer {
+    pub fn open(mut file: File, file_size: u64, allow_mmap: bool) -> Result<FileBuffer> {
+        if allow_mmap && file_size >= MMAP_THRESHOLD {
+            // SAFETY: the map is only read, and a file truncated by another
+            // process while it's mapped is no different to what any other
+            // grep that mmaps has to put up with
+            if let Ok(mmap) = unsafe { Mmap::map(&file) } {
+                return Ok(FileBuffer::Mmap(mmap));
+            }
+        }
+
+        let mut buf = Vec::with_capacity(file_size as usize);
+        file.read_to_end(&mut buf)?;
+        Ok(FileBuffer::Heap(buf))
+    }
+}
+
+impl Deref for FileBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FileBuffer::Heap(buf) => buf,
+            FileBuffer::Mmap(mmap) => mmap,
+        }
+    }
+}
+
+/// A line of a searched buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line<'a> {
+    pub line_num: usize,
+    /// Byte offset of the start of the line in the buffer.
+    pub offset: usize,
+    /// The line without its terminator.
+    pub bytes: &'a [u8],
+    /// Byte ranges of the matches within `bytes`, empty for context lines.
+    pub submatches: Vec<(usize, usize)>,
+    /// Edit distance of each submatch, only filled in with `--fuzzy`.
+    pub distances: Vec<usize>,
+    /// The first submatch is the rest of a match which started on an earlier
+    /// line, which only happens in multiline mode.
+    pub continued: bool,
+}
+
+/// Iterates over the lines of `buf` which contain a match.
+///
+/// The matcher runs over the whole buffer and line boundaries are only worked
+/// out around its hits, so lines without a match cost nothing but the scan.
+pub struct MatchingLines<'a> {
+    matcher: &'a Matcher,
+    buf: &'a [u8],
+    pos: usize,
+    line_num: usize,
+    counted: usize,
+}
+
+impl<'a> MatchingLines<'a> {
+    pub fn new(matcher: &'a Matcher, buf: &'a [u8]) -> Self {
+        MatchingLines {
+            matcher,
+            buf,
+            pos: 0,
+            line_num: 1,
+            counted: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for MatchingLines<'a> {
+    type Item = Line<'a>;
+
+    fn next(&mut self) -> Option<Line<'a>> {
+        let buf = self.buf;
+        while self.pos <= buf.len() {
+            let (start, _) = self.matcher.find_at(buf, self.pos)?;
+            let line_start = memrchr(b'\n', &buf[..start]).map_or(0, |i| i + 1);
+            // the empty "line" after a trailing newline isn't a line
+            if line_start == buf.len() {
+                return None;
+            }
+            let line_end = memchr(b'\n', &buf[start..]).map_or(buf.len(), |i| start + i);
+            self.pos = line_end + 1;
+
+            self.line_num += memchr_iter(b'\n', &buf[self.counted..line_start]).count();
+            self.counted = line_start;
+
+            // the hit may span several lines, so the line has to be checked
+            // on its own
+            let bytes = trim_line_terminator(&buf[line_start..line_end]);
+            let submatches = self.matcher.find_iter(bytes);
+            if !submatches.is_empty() {
+                return Some(Line {
+                    line_num: self.line_num,
+                    offset: line_start,
+                    bytes,
+                    distances: self.matcher.distances(bytes, &submatches),
+                    submatches,
+                    continued: false,
+                });
+            }
+        }
+
+        None
+    }
+}
+
+/// Iterates over the lines covered by matches which may span several lines,
+/// as used by `--multiline`. Matches touching the same line are merged, so
+/// every line is yielded once with all the match ranges that fall into it.
+pub struct MultilineMatches<'a> {
+    matcher: &'a Matcher,
+    buf: &'a [u8],
+    pos: usize,
+    line_num: usize,
+    counted: usize,
+    lookahead: Option<(usize, usize)>,
+    pending: VecDeque<Line<'a>>,
+}
+
+impl<'a> MultilineMatches<'a> {
+    pub fn new(matcher: &'a Matcher, buf: &'a [u8]) -> Self {
+        MultilineMatches {
+            matcher,
+            buf,
+            pos: 0,
+            line_num: 1,
+            counted: 0,
+            lookahead: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn next_match(&mut self) -> Option<(usize, usize)> {
+        if let Some(m) = self.lookahead.take() {
+            return Some(m);
+        }
+        if self.pos > self.buf.len() {
+            return None;
+        }
+        let (start, end) = self.matcher.find_at(self.buf, self.pos)?;
+        // step over empty matches so that they aren't found again
+        self.pos = if start == end { end + 1 } else { end };
+        Some((start, end))
+    }
+
+    /// Returns the offset of the line after the one containing the last byte
+    /// of the match.
+    fn covered_until(&self, start: usize, end: usize) -> usize {
+        let last = if end > start { end - 1 } else { end };
+        memchr(b'\n', &self.buf[last..]).map_or(self.buf.len(), |i| last + i + 1)
+    }
+}
+
+impl<'a> Iterator for MultilineMatches<'a> {
+    type Item = Line<'a>;
+
+    fn next(&mut self) -> Option<Line<'a>> {
+        if let Some(line) = self.pending.pop_front() {
+            return Some(line);
+        }
+
+        let buf = self.buf;
+        let (start, end) = self.next_match()?;
+        let first_line = memrchr(b'\n', &buf[..start]).map_or(0, |i| i + 1);
+        // the empty "line" after a trailing newline isn't a line
+        if first_line == buf.len() {
+            return None;
+        }
+
+        let mut spans = vec![(start, end)];
+        let mut until = self.covered_until(start, end);
+        while let Some((start, end)) = self.next_match() {
+            if start >= until {
+                self.lookahead = Some((start, end));
+                break;
+            }
+            spans.push((start, end));
+            until = until.max(self.covered_until(start, end));
+        }
+
+        self.line_num += memchr_iter(b'\n', &buf[self.counted..first_line]).count();
+        self.counted = first_line;
+
+        let mut offset = first_line;
+        let mut line_num = self.line_num;
+        while offset < until {
+            let (bytes, next_start) = line_at(buf, offset);
+            let content_end = offset + bytes.len();
+            let submatches = spans
+                .iter()
+                .filter(|&&(s, e)| s <= content_end && (e > offset || (s == e && s >= offset)))
+                .map(|&(s, e)| (s.max(offset) - offset, e.min(content_end) - offset))
+                .collect();
+            self.pending.push_back(Line {
+                line_num,
+                offset,
+                bytes,
+                submatches,
+                distances: Vec::new(),
+                continued: spans.iter().any(|&(s, e)| s < offset && e > offset),
+            });
+            offset = next_start;
+            line_num += 1;
+        }
+
+        self.pending.pop_front()
+    }
+}
+
+/// A context line to print, or a break between two groups of lines which
+/// aren't adjacent.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ContextItem<'a> {
+    Line(Line<'a>),
+    Break,
+}
+
+/// Works out the context lines around matched lines, making sure that no line
+/// is printed twice.
+pub struct Context<'a> {
+    buf: &'a [u8],
+    before: usize,
+    after: usize,
+    /// Offset and number of the first line after the last printed one.
+    next_offset: usize,
+    next_line_num: usize,
+    after_left: usize,
+    printed: bool,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(buf: &'a [u8], before: usize, after: usize) -> Self {
+        Context {
+            buf,
+            before,
+            after,
+            next_offset: 0,
+            next_line_num: 1,
+            after_left: 0,
+            printed: false,
+        }
+    }
+
+    /// Returns the items to print before the matched `line`.
+    pub fn before_match(&mut self, line: &Line) -> Vec<ContextItem<'a>> {
+        let mut items = self.after_context(line.offset);
+
+        let mut start = line.offset;
+        let mut line_num = line.line_num;
+        for _ in 0..self.before {
+            if start == 0 || start <= self.next_offset {
+                break;
+            }
+            start = memrchr(b'\n', &self.buf[..start - 1]).map_or(0, |i| i + 1);
+            line_num -= 1;
+        }
+
+        let has_context = self.before > 0 || self.after > 0;
+        if has_context && self.printed && start > self.next_offset {
+            items.push(ContextItem::Break);
+        }
+        while start < line.offset {
+            let (bytes, next_start) = line_at(self.buf, start);
+            items.push(ContextItem::Line(Line {
+                line_num,
+                offset: start,
+                bytes,
+                submatches: Vec::new(),
+                distances: Vec::new(),
+                continued: false,
+            }));
+            start = next_start;
+            line_num += 1;
+        }
+
+        self.next_offset = line_at(self.buf, line.offset).1;
+        self.next_line_num = line.line_num + 1;
+        self.after_left = self.after;
+        self.printed = true;
+
+        items
+    }
+
+    /// Returns the context lines left to print after the last match.
+    pub fn finish(&mut self) -> Vec<ContextItem<'a>> {
+        self.after_context(self.buf.len())
+    }
+
+    fn after_context(&mut self, limit: usize) -> Vec<ContextItem<'a>> {
+        let mut items = Vec::new();
+        while self.after_left > 0 && self.next_offset < limit {
+            let (bytes, next_start) = line_at(self.buf, self.next_offset);
+            items.push(ContextItem::Line(Line {
+                line_num: self.next_line_num,
+                offset: self.next_offset,
+                bytes,
+                submatches: Vec::new(),
+                distances: Vec::new(),
+                continued: false,
+            }));
+            self.next_offset = next_start;
+            self.next_line_num += 1;
+            self.after_left -= 1;
+        }
+        items
+    }
+}
+
+/// Returns the line starting at `offset` without its terminator, and the
+/// offset of the line after it.
+fn line_at(buf: &[u8], offset: usize) -> (&[u8], usize) {
+    match memchr(b'\n', &buf[offset..]) {
+        Some(i) => (
+            trim_line_terminator(&buf[offset..offset + i]),
+            offset + i + 1,
+        ),
+        None => (trim_line_terminator(&buf[offset..]), buf.len()),
+    }
+}
+
+/// Strips a trailing `\r` left over from a `\r\n` line terminator.
+fn trim_line_terminator(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::buffer::*;
+    use crate::matcher::MatcherOptions;
+
+    fn matching_lines(pattern: &str, buf: &[u8]) -> Vec<(usize, String)> {
+        let matcher = Matcher::new(&[pattern.to_string()], &MatcherOptions::default()).unwrap();
+        MatchingLines::new(&matcher, buf)
+            .map(|l| (l.line_num, String::from_utf8_lossy(l.bytes).into_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn find_matching_lines() {
+        let buf = b"foo\r\nbar\na\nb\nbar foo\n";
+        assert_eq!(
+            matching_lines("foo$", buf),
+            vec![(1, "foo".to_string()), (5, "bar foo".to_string())]
+        );
+        assert_eq!(matching_lines("^b", buf).len(), 3);
+        // a hit spanning two lines is no match
+        assert!(matching_lines(r"a\sb", buf).is_empty());
+        // no phantom line after the trailing newline
+        assert_eq!(matching_lines("^$", buf), vec![]);
+        assert_eq!(matching_lines("^", b""), vec![]);
+        assert_eq!(matching_lines("x", b"a\nx"), vec![(2, "x".to_string())]);
+    }
+
+    #[test]
+    fn keep_negated_classes_within_lines() {
+        let matcher =
+            Matcher::new(&[r"e[^x]*foo".to_string()], &MatcherOptions::default()).unwrap();
+        // the hit ends at the first line, instead of spanning to the last foo
+        assert_eq!(matcher.find_at(b"e foo\ne\nfoo\n", 0), Some((0, 5)));
+        assert_eq!(matcher.find_at(b"e\nfoo\n", 0), None);
+
+        // every line starts a hit which used to run to the end of the buffer,
+        // going over the rest of it once per line
+        let mut buf = b"e one\n".repeat(20_000);
+        buf.extend_from_slice(b"e foo\n");
+        let lines: Vec<_> = MatchingLines::new(&matcher, &buf)
+            .map(|l| (l.line_num, l.submatches))
+            .collect();
+        assert_eq!(lines, vec![(20_001, vec![(0, 5)])]);
+        assert_eq!(
+            matching_lines(r"a\sb", b"a\nb a b\n"),
+            vec![(2, "b a b".to_string())]
+        );
+    }
+
+    #[test]
+    fn print_context_once() {
+        let buf = b"1\n2\n3\nx\n5\nx\n7\n8\n9\nx\n";
+        let matcher = Matcher::new(&["x".to_string()], &MatcherOptions::default()).unwrap();
+        let mut context = Context::new(buf, 1, 1);
+        let mut printed = Vec::new();
+        for line in MatchingLines::new(&matcher, buf) {
+            for item in context.before_match(&line) {
+                printed.push(match item {
+                    ContextItem::Line(l) => l.line_num.to_string(),
+                    ContextItem::Break => "--".to_string(),
+                });
+            }
+            printed.push(format!("{}:", line.line_num));
+        }
+        assert!(context.finish().is_empty());
+        assert_eq!(printed, ["3", "4:", "5", "6:", "7", "--", "9", "10:"]);
+    }
+
+    #[test]
+    fn find_multiline_matches() {
+        let buf = b"a\nfoo(\nb)\nc foo() d\n";
+        let options = MatcherOptions {
+            multiline: true,
+            ..Default::default()
+        };
+        let matcher = Matcher::new(&[r"foo\([^)]*\)".to_string()], &options).unwrap();
+        let lines: Vec<_> = MultilineMatches::new(&matcher, buf)
+            .map(|l| (l.line_num, l.submatches))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![(2, vec![(0, 4)]), (3, vec![(0, 2)]), (4, vec![(2, 7)])]
+        );
+    }
+}
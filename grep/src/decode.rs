@@ -0,0 +1,119 @@
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use memchr::memchr_iter;
+
+/// How files are decoded before they're searched (`-E/--encoding`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decoding {
+    /// Decode files which start with a UTF-16 byte order mark. A UTF-8 one
+    /// is left in place, the file is searched and edited as it is.
+    Auto,
+    /// Search the raw bytes.
+    None,
+    /// Decode every file from this encoding.
+    Force(&'static Encoding),
+}
+
+impl Decoding {
+    /// Accepts `auto`, `none` or any WHATWG encoding label, like `utf-16le`,
+    /// `gbk`, `shift_jis` or `latin1`.
+    pub fn from_label(label: &str) -> Option<Decoding> {
+        match label {
+            "auto" => Some(Decoding::Auto),
+            "none" => Some(Decoding::None),
+            _ => Encoding::for_label(label.as_bytes()).map(Decoding::Force),
+        }
+    }
+
+    /// Returns the encoding `buf` has to be decoded from, or `None` if it's
+    /// searched as it is.
+    pub fn encoding_of(self, buf: &[u8]) -> Option<&'static Encoding> {
+        match self {
+            Decoding::Auto => match Encoding::for_bom(buf) {
+                Some((encoding, _)) if encoding != UTF_8 => Some(encoding),
+                _ => None,
+            },
+            Decoding::None => None,
+            Decoding::Force(encoding) => Some(encoding),
+        }
+    }
+}
+
+/// A buffer transcoded to UTF-8, which remembers where its lines start in
+/// the original bytes so that offsets can be reported against the file.
+#[derive(Debug)]
+pub struct Decoded {
+    pub text: Vec<u8>,
+    line_starts: Vec<usize>,
+}
+
+impl Decoded {
+    pub fn new(buf: &[u8], encoding: &'static Encoding) -> Decoded {
+        let bom_len = match Encoding::for_bom(buf) {
+            Some((bom_encoding, len)) if bom_encoding == encoding => len,
+            _ => 0,
+        };
+        let body = &buf[bom_len..];
+        let text = encoding
+            .decode_without_bom_handling(body)
+            .0
+            .into_owned()
+            .into_bytes();
+
+        // every encoding keeps line feeds as they are, so the nth line of the
+        // text is the nth line of the original
+        let mut line_starts = vec![bom_len];
+        if encoding == UTF_16LE || encoding == UTF_16BE {
+            let newline: &[u8] = if encoding == UTF_16LE {
+                b"\n\0"
+            } else {
+                b"\0\n"
+            };
+            line_starts.extend(
+                body.chunks_exact(2)
+                    .enumerate()
+                    .filter(|(_, unit)| *unit == newline)
+                    .map(|(i, _)| bom_len + 2 * i + 2),
+            );
+        } else {
+            line_starts.extend(memchr_iter(b'\n', body).map(|i| bom_len + i + 1));
+        }
+
+        Decoded { text, line_starts }
+    }
+
+    /// Returns the offset in the original bytes of the start of line
+    /// `line_num`, falling back to `offset` in the text if the decoder didn't
+    /// keep the lines.
+    pub fn original_offset(&self, line_num: usize, offset: usize) -> usize {
+        self.line_starts
+            .get(line_num - 1)
+            .copied()
+            .unwrap_or(offset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::decode::*;
+
+    #[test]
+    fn decode_utf16_with_bom() {
+        let mut buf = vec![0xff, 0xfe];
+        for unit in "héllo\r\nwörld\n".encode_utf16() {
+            buf.extend_from_slice(&unit.to_le_bytes());
+        }
+        let encoding = Decoding::Auto.encoding_of(&buf).unwrap();
+        assert_eq!(encoding, UTF_16LE);
+
+        let decoded = Decoded::new(&buf, encoding);
+        assert_eq!(decoded.text, "héllo\r\nwörld\n".as_bytes());
+        assert_eq!(decoded.original_offset(1, 0), 2);
+        assert_eq!(decoded.original_offset(2, 8), 16);
+
+        assert_eq!(Decoding::Auto.encoding_of(b"plain"), None);
+        assert_eq!(Decoding::Auto.encoding_of(b"\xef\xbb\xbfplain"), None);
+        let latin1 = Decoding::from_label("latin1").unwrap();
+        let decoded = Decoded::new(b"caf\xe9\n", latin1.encoding_of(b"").unwrap());
+        assert_eq!(decoded.text, "café\n".as_bytes());
+    }
+}
@@ -0,0 +1,158 @@
+/// Approximate matching of literal patterns, agrep style: a hit may differ
+/// from a pattern by up to `max_distance` inserted, deleted or substituted
+/// bytes.
+#[derive(Debug, Clone)]
+pub struct Fuzzy {
+    patterns: Vec<Pattern>,
+    max_distance: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    bytes: Vec<u8>,
+    /// Compare ASCII letters regardless of case.
+    ignore_case: bool,
+}
+
+impl Pattern {
+    fn eq(&self, a: u8, b: u8) -> bool {
+        a == b || (self.ignore_case && a.eq_ignore_ascii_case(&b))
+    }
+}
+
+impl Fuzzy {
+    /// Every pattern has to be longer than `max_distance`, or it would match
+    /// the empty string everywhere. The patterns for which `ignore_case`
+    /// returns true match regardless of ASCII case.
+    pub fn new<F>(patterns: &[String], max_distance: usize, ignore_case: F) -> Option<Fuzzy>
+    where
+        F: Fn(&str) -> bool,
+    {
+        if patterns.iter().any(|p| p.len() <= max_distance) {
+            return None;
+        }
+        Some(Fuzzy {
+            patterns: patterns
+                .iter()
+                .map(|p| Pattern {
+                    bytes: p.as_bytes().to_vec(),
+                    ignore_case: ignore_case(p),
+                })
+                .collect(),
+            max_distance,
+        })
+    }
+
+    /// Returns the first hit in `text` which starts at or after `from`, with
+    /// its edit distance. Among overlapping candidates the closest one wins.
+    pub fn find(&self, text: &[u8], from: usize) -> Option<(usize, usize, usize)> {
+        self.patterns
+            .iter()
+            .filter_map(|pattern| self.find_pattern(pattern, text, from))
+            .min_by_key(|&(start, end, distance)| (start, distance, end))
+    }
+
+    pub fn find_iter(&self, text: &[u8]) -> Vec<(usize, usize)> {
+        let mut hits = Vec::new();
+        let mut from = 0;
+        while let Some((start, end, _)) = self.find(text, from) {
+            hits.push((start, end));
+            from = end;
+        }
+        hits
+    }
+
+    /// Returns the smallest edit distance between `span` and a pattern.
+    pub fn distance(&self, span: &[u8]) -> usize {
+        self.patterns
+            .iter()
+            .map(|pattern| levenshtein(pattern, span))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Sellers' algorithm: the edit distance between the pattern and the best
+    /// substring of `text` ending at each position, computed column by column
+    /// along with where that substring starts.
+    fn find_pattern(
+        &self,
+        pattern: &Pattern,
+        text: &[u8],
+        from: usize,
+    ) -> Option<(usize, usize, usize)> {
+        let m = pattern.bytes.len();
+        let mut dist: Vec<usize> = (0..=m).collect();
+        let mut start = vec![from; m + 1];
+        let mut best: Option<(usize, usize, usize)> = None;
+
+        for (j, &byte) in text.iter().enumerate().skip(from) {
+            let (mut diag, mut diag_start) = (dist[0], start[0]);
+            dist[0] = 0;
+            start[0] = j + 1;
+            for i in 1..=m {
+                let substitute = diag + !pattern.eq(pattern.bytes[i - 1], byte) as usize;
+                let insert = dist[i] + 1;
+                let delete = dist[i - 1] + 1;
+                let (d, s) = if substitute <= insert && substitute <= delete {
+                    (substitute, diag_start)
+                } else if insert <= delete {
+                    (insert, start[i])
+                } else {
+                    (delete, start[i - 1])
+                };
+                diag = dist[i];
+                diag_start = start[i];
+                dist[i] = d;
+                start[i] = s;
+            }
+
+            // keep going while still within budget, the hit may get closer
+            if dist[m] <= self.max_distance {
+                if best.is_none_or(|(_, _, d)| dist[m] < d) {
+                    best = Some((start[m], j + 1, dist[m]));
+                }
+            } else if best.is_some() {
+                break;
+            }
+        }
+
+        best
+    }
+}
+
+fn levenshtein(pattern: &Pattern, b: &[u8]) -> usize {
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, &x) in pattern.bytes.iter().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, &y) in b.iter().enumerate() {
+            let d = (diag + !pattern.eq(x, y) as usize)
+                .min(row[j + 1] + 1)
+                .min(row[j] + 1);
+            diag = row[j + 1];
+            row[j + 1] = d;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fuzzy::*;
+
+    #[test]
+    fn match_within_edit_distance() {
+        let fuzzy = Fuzzy::new(&["connection".to_string()], 2, |_| false).unwrap();
+        let text = b"lost conection, retry conneciton now, conn";
+        assert_eq!(fuzzy.find_iter(text), vec![(5, 14), (22, 32)]);
+        assert_eq!(fuzzy.distance(&text[5..14]), 1);
+        assert_eq!(fuzzy.distance(&text[22..32]), 2);
+        assert_eq!(fuzzy.find(b"connection", 0), Some((0, 10, 0)));
+        assert_eq!(fuzzy.find(b"commotion", 0), None);
+
+        assert!(Fuzzy::new(&["ab".to_string()], 2, |_| false).is_none());
+
+        let fuzzy = Fuzzy::new(&["connection".to_string()], 1, |_| true).unwrap();
+        assert_eq!(fuzzy.find(b"CONECTION", 0), Some((0, 9, 1)));
+    }
+}
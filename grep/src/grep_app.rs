@@ -1,49 +1,182 @@
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg};
 use crossbeam::channel::{self, Sender};
-use regex::Regex;
-
-use std::fs::{self, File};
-use std::io::{BufRead, BufReader, BufWriter, Result, StdoutLock, Write};
-#[cfg(target_os = "linux")]
-use std::os::linux::fs::MetadataExt;
-#[cfg(target_os = "unix")]
-use std::os::unix::fs::MetadataExt;
-#[cfg(target_os = "windows")]
-use std::os::windows::fs::MetadataExt;
-use std::path::PathBuf;
+use ignore::{DirEntry, WalkState};
+use regex::bytes::Regex;
+
+use grep::archive::{Archive, Compression};
+use grep::buffer::FileBuffer;
+use grep::decode::Decoding;
+use grep::index::{Index, TrigramQuery};
+use grep::matcher::{Case, Matcher, MatcherOptions};
+use grep::printer::{
+    self, Colors, CountPrinter, FilesPrinter, JsonPrinter, Printer, StandardPrinter, VimgrepPrinter,
+};
+use grep::replace::{self, ReplaceSink};
+use grep::searcher::{BinaryFiles, Searcher};
+use grep::stats::{FileStats, Stats};
+use grep::walk::{self, GlobFilter, SkipCounter, WalkConfig};
+use grep::watch::{Changes, Watched};
+
+use std::collections::HashSet;
+use std::env;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufWriter, IsTerminal, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Instant, SystemTime};
 
 const K: u64 = 1024;
 
 #[derive(Debug)]
 pub struct GrepApp {
-    pattern: Regex,
-    files: Option<Vec<String>>,
+    searcher: Searcher,
+    files: Option<Vec<PathBuf>>,
     dirs: Option<Vec<String>>,
     recursive: bool,
+    walk_config: WalkConfig,
+    no_messages: bool,
+    mmap: bool,
+    search_zip: bool,
+    search_archives: bool,
+    printer: Box<dyn Printer>,
+    print_stats: bool,
+    count_skipped: bool,
+    replace: Option<Vec<u8>>,
+    in_place: bool,
+    /// The real paths of the files edited in place, so that a file reached
+    /// through several paths is only edited once.
+    edited: Mutex<HashSet<PathBuf>>,
+    dry_run: bool,
     thread_num: usize,
+    list_files: bool,
+    null: bool,
+    index_query: Option<TrigramQuery>,
+    watched: Option<Mutex<Watched>>,
+    matched: AtomicBool,
+    errored: AtomicBool,
+    stats: Stats,
 }
 
 impl GrepApp {
     pub fn new() -> Self {
         GrepApp {
-            pattern: Regex::new("a").unwrap(),
+            searcher: Searcher::new(Matcher::Regex(Regex::new("a").unwrap())),
             files: None,
             dirs: None,
             recursive: false,
+            walk_config: WalkConfig::default(),
+            no_messages: false,
+            mmap: true,
+            search_zip: false,
+            search_archives: false,
+            printer: Box::new(StandardPrinter::default()),
+            print_stats: false,
+            count_skipped: false,
+            replace: None,
+            in_place: false,
+            edited: Mutex::default(),
+            dry_run: false,
             thread_num: 4,
+            list_files: false,
+            null: false,
+            index_query: None,
+            watched: None,
+            matched: AtomicBool::new(false),
+            errored: AtomicBool::new(false),
+            stats: Stats::default(),
         }
     }
 
     pub fn get_args(&mut self) {
+        let mut args: Vec<OsString> = env::args_os().collect();
+        if !args.iter().any(|arg| arg == "--no-config") {
+            let config = config_args().expect("Failed to read config file: ");
+            let at = args.len().min(1);
+            args.splice(at..at, config);
+        }
+
         let matches = App::new("grep")
+            // the command line overrides what the config file sets
+            .setting(AppSettings::AllArgsOverrideSelf)
             .arg(Arg::new("PATTERN").takes_value(true))
+            .arg(
+                Arg::new("REGEXP")
+                    .long("regexp")
+                    .short('e')
+                    .help("use PATTERN for matching, can be given multiple times")
+                    .takes_value(true)
+                    .value_name("PATTERN")
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("PATTERNS-FILE")
+                    .long("patterns-file")
+                    .help("take patterns from FILE, one per line")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("fixed-strings")
+                    .long("fixed-strings")
+                    .short('F')
+                    .help("interpret patterns as fixed strings, not regular expressions"),
+            )
+            .arg(
+                Arg::new("ignore-case")
+                    .long("ignore-case")
+                    .short('i')
+                    .help("ignore case distinctions in patterns")
+                    .overrides_with("smart-case"),
+            )
+            .arg(
+                Arg::new("smart-case")
+                    .long("smart-case")
+                    .short('S')
+                    .help("ignore case in patterns without an uppercase letter")
+                    .overrides_with("ignore-case"),
+            )
+            .arg(
+                Arg::new("perl-regexp")
+                    .long("perl-regexp")
+                    .short('P')
+                    .help("use a backtracking engine, which supports look-around and backreferences")
+                    .conflicts_with("fixed-strings"),
+            )
+            .arg(
+                Arg::new("FUZZY")
+                    .long("fuzzy")
+                    .help("match patterns as literals with up to K inserted, deleted or substituted bytes")
+                    .takes_value(true)
+                    .value_name("K")
+                    .conflicts_with_all(&["perl-regexp", "multiline"]),
+            )
+            .arg(
+                Arg::new("multiline")
+                    .long("multiline")
+                    .short('U')
+                    .help("let matches span multiple lines, every line of a match is printed")
+                    .conflicts_with("REPLACE"),
+            )
+            .arg(
+                Arg::new("multiline-dotall")
+                    .long("multiline-dotall")
+                    .help("let `.` match newlines too in --multiline mode")
+                    .requires("multiline"),
+            )
             .arg(
                 Arg::new("FILE")
                     .long("file")
                     .short('f')
                     .help("FILE(s) to match")
                     .takes_value(true)
-                    .multiple_values(true),
+                    .multiple_values(true)
+                    .allow_invalid_utf8(true),
             )
             .arg(
                 Arg::new("DIR")
@@ -53,12 +186,306 @@ impl GrepApp {
                     .takes_value(true)
                     .multiple_values(true),
             )
+            .arg(
+                Arg::new("FILES-FROM")
+                    .long("files-from")
+                    .help("match the paths listed in FILE, separated by NUL or newline, `-` reads stdin")
+                    .takes_value(true)
+                    .value_name("FILE"),
+            )
+            .arg(
+                Arg::new("files")
+                    .long("files")
+                    .help("print the files that would be searched instead of searching them"),
+            )
+            .arg(
+                Arg::new("null")
+                    .long("null")
+                    .short('0')
+                    .help("end file names printed by --files or -l with NUL instead of newline"),
+            )
+            .arg(
+                Arg::new("INDEX")
+                    .long("index")
+                    .help("`--index build DIR` writes a trigram index of DIR, updating only changed files")
+                    .takes_value(true)
+                    .number_of_values(2)
+                    .value_names(&["ACTION", "DIR"]),
+            )
+            .arg(
+                Arg::new("use-index")
+                    .long("use-index")
+                    .help("skip the files in DIR(s) which the index rules out, results stay the same"),
+            )
             .arg(
                 Arg::new("recursive")
                     .short('r')
                     .help("match recursively")
                     .conflicts_with("FILE"),
             )
+            .arg(
+                Arg::new("hidden")
+                    .long("hidden")
+                    .help("search hidden files and directories"),
+            )
+            .arg(
+                Arg::new("follow")
+                    .long("follow")
+                    .short('L')
+                    .help("follow symbolic links while walking directories, loops are reported"),
+            )
+            .arg(
+                Arg::new("one-file-system")
+                    .long("one-file-system")
+                    .help("don't descend into directories on other file systems"),
+            )
+            .arg(
+                Arg::new("no-ignore")
+                    .long("no-ignore")
+                    .help("don't respect .gitignore, .ignore and global git excludes"),
+            )
+            .arg(
+                Arg::new("INCLUDE")
+                    .long("include")
+                    .help("search only files whose base name matches GLOB")
+                    .takes_value(true)
+                    .value_name("GLOB")
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("TYPE")
+                    .long("type")
+                    .short('t')
+                    .help("search only files of TYPE, see --type-list")
+                    .takes_value(true)
+                    .value_name("TYPE")
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("TYPE-NOT")
+                    .long("type-not")
+                    .short('T')
+                    .help("don't search files of TYPE")
+                    .takes_value(true)
+                    .value_name("TYPE")
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("TYPE-ADD")
+                    .long("type-add")
+                    .help("add a file type as NAME:GLOB, or extend an existing one")
+                    .takes_value(true)
+                    .value_name("NAME:GLOB")
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("type-list")
+                    .long("type-list")
+                    .help("print the known file types and exit"),
+            )
+            .arg(
+                Arg::new("EXCLUDE")
+                    .long("exclude")
+                    .help("skip files whose base name matches GLOB")
+                    .takes_value(true)
+                    .value_name("GLOB")
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("EXCLUDE-DIR")
+                    .long("exclude-dir")
+                    .help("skip directories whose base name matches GLOB")
+                    .takes_value(true)
+                    .value_name("GLOB")
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("MAX-DEPTH")
+                    .long("max-depth")
+                    .help("descend at most NUM levels below DIR(s) when matching recursively")
+                    .takes_value(true)
+                    .value_name("NUM"),
+            )
+            .arg(
+                Arg::new("MAX-FILESIZE")
+                    .long("max-filesize")
+                    .help("skip files larger than SIZE, e.g. 512K, 10M or 1G")
+                    .takes_value(true)
+                    .value_name("SIZE"),
+            )
+            .arg(
+                Arg::new("NEWER-THAN")
+                    .long("newer-than")
+                    .help("search only files modified after WHEN, an age like 2d, 3h or 15m, or a UTC date like 2024-01-31")
+                    .takes_value(true)
+                    .value_name("WHEN"),
+            )
+            .arg(
+                Arg::new("OLDER-THAN")
+                    .long("older-than")
+                    .help("search only files modified before WHEN, same format as --newer-than")
+                    .takes_value(true)
+                    .value_name("WHEN"),
+            )
+            .arg(
+                Arg::new("OWNER")
+                    .long("owner")
+                    .help("search only files owned by USER, a name or a user id")
+                    .takes_value(true)
+                    .value_name("USER"),
+            )
+            .arg(
+                Arg::new("text")
+                    .long("text")
+                    .short('a')
+                    .help("process a binary file as if it were text, same as --binary-files=text"),
+            )
+            .arg(
+                Arg::new("BINARY-FILES")
+                    .long("binary-files")
+                    .help("assume that binary files are TYPE")
+                    .takes_value(true)
+                    .value_name("TYPE")
+                    .possible_values(["binary", "text", "without-match"])
+                    .default_value("binary"),
+            )
+            .arg(
+                Arg::new("no-messages")
+                    .long("no-messages")
+                    .short('s')
+                    .help("suppress error messages about nonexistent or unreadable files"),
+            )
+            .arg(
+                Arg::new("AFTER-CONTEXT")
+                    .long("after-context")
+                    .short('A')
+                    .help("print NUM lines of trailing context after matching lines")
+                    .takes_value(true)
+                    .value_name("NUM"),
+            )
+            .arg(
+                Arg::new("BEFORE-CONTEXT")
+                    .long("before-context")
+                    .short('B')
+                    .help("print NUM lines of leading context before matching lines")
+                    .takes_value(true)
+                    .value_name("NUM"),
+            )
+            .arg(
+                Arg::new("CONTEXT")
+                    .long("context")
+                    .short('C')
+                    .help("print NUM lines of output context")
+                    .takes_value(true)
+                    .value_name("NUM"),
+            )
+            .arg(
+                Arg::new("COLOR")
+                    .long("color")
+                    .help("when to use colors, auto uses them on a terminal unless NO_COLOR is set, GREP_COLORS customises them")
+                    .takes_value(true)
+                    .value_name("WHEN")
+                    .possible_values(["auto", "always", "never"])
+                    .default_value("auto"),
+            )
+            .arg(
+                Arg::new("json")
+                    .long("json")
+                    .help("print results as JSON Lines, one object per event"),
+            )
+            .arg(
+                Arg::new("vimgrep")
+                    .long("vimgrep")
+                    .help("print every match as path:line:column:text, for an editor's quickfix list")
+                    .conflicts_with_all(&["json", "count", "files-with-matches", "byte-offset"]),
+            )
+            .arg(
+                Arg::new("byte-offset")
+                    .long("byte-offset")
+                    .short('b')
+                    .help("print the byte offset of every line within its file"),
+            )
+            .arg(
+                Arg::new("column")
+                    .long("column")
+                    .help("print the 1-based column of the first match of every matching line"),
+            )
+            .arg(
+                Arg::new("count")
+                    .long("count")
+                    .short('c')
+                    .help("print only the number of matching lines of each file")
+                    .conflicts_with_all(&["json", "REPLACE"]),
+            )
+            .arg(
+                Arg::new("files-with-matches")
+                    .long("files-with-matches")
+                    .short('l')
+                    .help("print only the names of the files which match")
+                    .conflicts_with_all(&["json", "count", "REPLACE"]),
+            )
+            .arg(
+                Arg::new("stats")
+                    .long("stats")
+                    .help("print totals after the search: files searched, skipped and with matches, lines, bytes and time"),
+            )
+            .arg(
+                Arg::new("REPLACE")
+                    .long("replace")
+                    .help("print matching lines with every match replaced by TEMPLATE, $1 or ${name} expand to capture groups")
+                    .takes_value(true)
+                    .value_name("TEMPLATE")
+                    .allow_hyphen_values(true),
+            )
+            .arg(
+                Arg::new("in-place")
+                    .long("in-place")
+                    .help("write the replacements back to the files")
+                    .requires("REPLACE"),
+            )
+            .arg(
+                Arg::new("dry-run")
+                    .long("dry-run")
+                    .help("print a unified diff of what --in-place would change, without changing anything")
+                    .requires("REPLACE")
+                    .conflicts_with("in-place"),
+            )
+            .arg(
+                Arg::new("search-zip")
+                    .long("search-zip")
+                    .short('z')
+                    .help("search in .gz, .xz and .zst compressed files"),
+            )
+            .arg(
+                Arg::new("search-archives")
+                    .long("search-archives")
+                    .help("search the members of tar and zip archives"),
+            )
+            .arg(
+                Arg::new("ENCODING")
+                    .long("encoding")
+                    .short('E')
+                    .help("decode files from LABEL, e.g. utf-16le, gbk, shift_jis or latin1; auto only decodes files with a UTF-16 BOM, none never decodes")
+                    .takes_value(true)
+                    .value_name("LABEL")
+                    .default_value("auto"),
+            )
+            .arg(
+                Arg::new("no-mmap")
+                    .long("no-mmap")
+                    .help("never memory-map files, always read them into memory"),
+            )
+            .arg(
+                Arg::new("watch")
+                    .long("watch")
+                    .help("search again whenever a searched file changes, until Ctrl-C")
+                    .conflicts_with_all(&["in-place", "files"]),
+            )
+            .arg(
+                Arg::new("no-config")
+                    .long("no-config")
+                    .help("don't read default arguments from GREP_CONFIG_PATH or ~/.config/grep/config"),
+            )
             .arg(
                 Arg::new("N-THREAD")
                     .long("n-thread")
@@ -69,263 +496,692 @@ impl GrepApp {
             .version("0.1.0")
             .author("朕与将军解战袍, 1393323447@qq.com")
             .about("match content in file or directory")
-            .get_matches();
+            .get_matches_from(args);
 
-        self.pattern = Regex::new(
+        let values = |name| {
             matches
-                .value_of("PATTERN")
-                .expect("Please provide a PATTERN to match"),
-        )
-        .expect("Invalid pattern: ");
+                .values_of(name)
+                .map(|values| values.collect())
+                .unwrap_or_else(Vec::new)
+        };
+        if matches.is_present("type-list") {
+            let builder = walk::types_builder(&values("TYPE-ADD")).expect("Invalid file type: ");
+            for def in builder.definitions() {
+                println!("{}: {}", def.name(), def.globs().join(", "));
+            }
+            process::exit(0);
+        }
+
+        let mut patterns: Vec<String> = Vec::new();
+        if let Some(pattern) = matches.value_of("PATTERN") {
+            patterns.push(pattern.to_string());
+        }
+        if let Some(values) = matches.values_of("REGEXP") {
+            patterns.extend(values.map(|s| s.to_string()));
+        }
+        if let Some(values) = matches.values_of("PATTERNS-FILE") {
+            for path in values {
+                let content = fs::read_to_string(path).expect("Failed to read patterns file: ");
+                patterns.extend(content.lines().map(|s| s.to_string()));
+            }
+        }
+        self.list_files = matches.is_present("files");
+        self.null = matches.is_present("null");
+        let index_dir = matches.values_of("INDEX").map(|mut values| {
+            if values.next() != Some("build") {
+                panic!("Unknown index action, expected `--index build DIR`");
+            }
+            values.next().unwrap().to_string()
+        });
+        // --files and --index need no pattern, any pattern given is ignored
+        if !self.list_files && index_dir.is_none() {
+            if patterns.is_empty() {
+                panic!("Please provide a PATTERN to match");
+            }
+            let options = MatcherOptions {
+                fixed_strings: matches.is_present("fixed-strings"),
+                dot_matches_new_line: matches.is_present("multiline-dotall"),
+                multiline: matches.is_present("multiline"),
+                backtrack: matches.is_present("perl-regexp"),
+                fuzzy: matches
+                    .value_of("FUZZY")
+                    .map(|s| s.parse().expect("Invalid edit distance: ")),
+                case: if matches.is_present("smart-case") {
+                    Case::Smart
+                } else if matches.is_present("ignore-case") {
+                    Case::Insensitive
+                } else {
+                    Case::Sensitive
+                },
+            };
+            self.searcher.matcher = Matcher::new(&patterns, &options)
+                .unwrap_or_else(|err| panic!("Invalid pattern: {}", err));
+            // decompressed or transcoded content isn't what was indexed, and
+            // fuzzy hits don't have to contain any trigram of the patterns
+            let indexable = !matches.is_present("search-zip")
+                && !matches.is_present("search-archives")
+                && matches!(matches.value_of("ENCODING"), Some("auto" | "none"))
+                && options.fuzzy.is_none();
+            if matches.is_present("use-index") && indexable {
+                self.index_query = TrigramQuery::new(&patterns, &options);
+            }
+        }
+        self.searcher.multiline = matches.is_present("multiline");
         self.files = matches
-            .values_of("FILE")
-            .map(|values| values.map(|s| s.to_string()).collect());
+            .values_of_os("FILE")
+            .map(|values| values.map(PathBuf::from).collect());
+        if let Some(path) = matches.value_of("FILES-FROM") {
+            let files = self.files.get_or_insert_with(Vec::new);
+            files.extend(read_path_list(path).expect("Failed to read files-from list: "));
+        }
         self.dirs = matches
             .values_of("DIR")
             .map(|values| values.map(|s| s.to_string()).collect());
-        if self.files.is_none() && self.dirs.is_none() {
+        if self.files.is_none() && self.dirs.is_none() && index_dir.is_none() {
             panic!("Please specify FILE(s) or DIR(s) to match");
         }
         self.recursive = matches.is_present("recursive");
+
+        self.walk_config.filter = GlobFilter::new(
+            &values("INCLUDE"),
+            &values("EXCLUDE"),
+            &values("EXCLUDE-DIR"),
+        )
+        .expect("Invalid glob: ");
+        self.walk_config.types =
+            walk::file_types(&values("TYPE-ADD"), &values("TYPE"), &values("TYPE-NOT"))
+                .expect("Invalid file type: ");
+        self.walk_config.hidden = matches.is_present("hidden");
+        self.walk_config.no_ignore = matches.is_present("no-ignore");
+        self.walk_config.follow_links = matches.is_present("follow");
+        self.walk_config.one_file_system = matches.is_present("one-file-system");
+        self.walk_config.max_depth = matches
+            .value_of("MAX-DEPTH")
+            .map(|s| s.parse().expect("Invalid max depth: "));
+        let now = SystemTime::now();
+        let time_arg = |name| {
+            matches
+                .value_of(name)
+                .map(|s| walk::parse_time(s, now).expect("Invalid time: "))
+        };
+        self.walk_config.metadata.max_filesize = matches
+            .value_of("MAX-FILESIZE")
+            .map(|s| walk::parse_size(s).expect("Invalid size: "));
+        self.walk_config.metadata.newer_than = time_arg("NEWER-THAN");
+        self.walk_config.metadata.older_than = time_arg("OLDER-THAN");
+        self.walk_config.metadata.owner = matches
+            .value_of("OWNER")
+            .map(|s| walk::user_id(s).expect("Unknown owner: "));
+        if let Some(dir) = index_dir {
+            let stats = Index::build(Path::new(&dir), &self.walk_config)
+                .unwrap_or_else(|err| panic!("Failed to build index of {}: {}", dir, err));
+            println!(
+                "Indexed {} files in {}, {} of them changed",
+                stats.files, dir, stats.updated
+            );
+            process::exit(0);
+        }
+        self.searcher.binary_files = if matches.is_present("text") {
+            BinaryFiles::Text
+        } else {
+            match matches.value_of("BINARY-FILES") {
+                Some("text") => BinaryFiles::Text,
+                Some("without-match") => BinaryFiles::WithoutMatch,
+                _ => BinaryFiles::Binary,
+            }
+        };
+        self.no_messages = matches.is_present("no-messages");
+        self.mmap = !matches.is_present("no-mmap");
+        self.searcher.decoding = matches
+            .value_of("ENCODING")
+            .and_then(Decoding::from_label)
+            .expect("Unknown encoding: ");
+        self.search_zip = matches.is_present("search-zip");
+        self.search_archives = matches.is_present("search-archives");
+
+        let context_arg = |name| {
+            matches
+                .value_of(name)
+                .map(|s| s.parse::<usize>().expect("Invalid context length: "))
+        };
+        let context = context_arg("CONTEXT").unwrap_or(0);
+        self.searcher.before_context = context_arg("BEFORE-CONTEXT").unwrap_or(context);
+        self.searcher.after_context = context_arg("AFTER-CONTEXT").unwrap_or(context);
+        self.printer = if matches.is_present("json") {
+            Box::new(JsonPrinter)
+        } else if matches.is_present("count") {
+            Box::new(CountPrinter)
+        } else if matches.is_present("files-with-matches") {
+            Box::new(FilesPrinter::new(self.null))
+        } else if matches.is_present("vimgrep") {
+            Box::new(VimgrepPrinter)
+        } else {
+            let color = match matches.value_of("COLOR") {
+                Some("always") => true,
+                Some("never") => false,
+                _ => {
+                    io::stdout().is_terminal()
+                        && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                }
+            };
+            let colors = color.then(|| match env::var("GREP_COLORS") {
+                Ok(spec) => Colors::default().with_spec(&spec),
+                Err(_) => Colors::default(),
+            });
+            Box::new(
+                StandardPrinter::new(colors)
+                    .byte_offset(matches.is_present("byte-offset"))
+                    .column(matches.is_present("column")),
+            )
+        };
+        // the JSON summary has the totals anyway
+        self.print_stats = matches.is_present("stats") && !matches.is_present("json");
+        self.count_skipped = matches.is_present("stats") || matches.is_present("json");
+        self.replace = matches.value_of("REPLACE").map(|s| s.as_bytes().to_vec());
+        self.in_place = matches.is_present("in-place");
+        self.dry_run = matches.is_present("dry-run");
+        self.watched = matches.is_present("watch").then(Mutex::default);
         self.thread_num = matches.value_of("N-THREAD").unwrap_or("4").parse().unwrap();
     }
 
-    pub fn run(&self) -> Result<()> {
+    /// Runs the search and returns the exit status: 0 if any line matched,
+    /// 1 if none did and 2 if an error occurred.
+    pub fn run(self) -> Result<i32> {
         rayon::ThreadPoolBuilder::new()
             .num_threads(self.thread_num)
             .build_global()
             .unwrap();
 
+        let start = Instant::now();
         let stdout = std::io::stdout();
         let mut writer = BufWriter::new(stdout.lock());
         let (sender, receiver) = channel::unbounded();
 
-        self.search_and_match_files(sender.clone(), &mut writer)?;
-        drop(sender);
+        // walk on a separate thread so that results are printed while the
+        // walk is still going
+        let app = Arc::new(self);
+        let searcher = {
+            let app = Arc::clone(&app);
+            thread::spawn(move || app.search_and_match_files(sender))
+        };
 
         while let Ok(buf) = receiver.recv() {
             writer.write_all(&buf)?;
         }
+        searcher.join().expect("Search thread panicked: ");
+        if !app.list_files {
+            let (totals, elapsed) = (app.stats.totals(), start.elapsed());
+            app.printer.summary(&mut writer, &totals, elapsed)?;
+            if app.print_stats {
+                printer::write_stats(&mut writer, &totals, elapsed)?;
+            }
+        }
+        writer.flush()?;
+        if app.watched.is_some() {
+            app.watch(&mut writer)?;
+        }
 
-        Ok(())
+        let status = if app.errored.load(Ordering::Relaxed) {
+            2
+        } else if app.matched.load(Ordering::Relaxed) {
+            0
+        } else {
+            1
+        };
+
+        Ok(status)
     }
 
-    fn search_and_match_files(
-        &self,
-        sender: Sender<Vec<u8>>,
-        writer: &mut BufWriter<StdoutLock>,
-    ) -> Result<()> {
-        let mut path = PathBuf::new();
+    fn search_and_match_files(self: &Arc<Self>, sender: Sender<Vec<u8>>) {
         if let Some(ref files) = self.files {
             for filepath in files {
-                path.push(filepath);
-                self.start_match(&path, sender.clone(), writer)?;
-                path.clear();
+                self.start_match(filepath.clone(), None, &sender);
             }
         }
 
         if let Some(ref dirs) = self.dirs {
-            if self.recursive {
-                for dir in dirs {
-                    self.search_files_recursively(dir, sender.clone(), writer)?;
-                }
+            let max_depth = if self.recursive {
+                self.walk_config.max_depth
             } else {
-                for dir in dirs {
-                    self.search_files_nonreursively(dir, sender.clone(), writer)?;
-                }
+                Some(1)
+            };
+            for dir in dirs {
+                let index = self.load_index(dir);
+                self.search_dir(dir, max_depth, index, &sender);
             }
         }
+    }
 
-        Ok(())
+    /// Loads the index of `dir` if the search goes through one. Without an
+    /// index every file is searched, so the results are the same and only a
+    /// warning is printed.
+    fn load_index(&self, dir: &str) -> Option<Arc<Index>> {
+        self.index_query.as_ref()?;
+        match Index::load(Path::new(dir)) {
+            Ok(Some(index)) => Some(Arc::new(index)),
+            Ok(None) => {
+                self.report_warning(format_args!(
+                    "{}: no index, build it with `--index build {}`",
+                    dir, dir
+                ));
+                None
+            }
+            Err(err) => {
+                self.report_warning(format_args!("{}: failed to load index: {}", dir, err));
+                None
+            }
+        }
     }
 
-    fn search_files_recursively(
-        &self,
+    /// Walks `dir` with a pool of work-stealing walker threads. Small files
+    /// are matched by the walker thread that found them, larger ones are
+    /// handed over to the rayon pool.
+    fn search_dir(
+        self: &Arc<Self>,
         dir: &str,
-        sender: Sender<Vec<u8>>,
-        writer: &mut BufWriter<StdoutLock>,
-    ) -> Result<()> {
-        let mut dirs = vec![dir.into()];
-        while !dirs.is_empty() {
-            let dir = dirs.pop().unwrap();
-            let entries = fs::read_dir(dir)?;
-            for entry in entries {
-                let entry = entry?;
-                let file_type = entry.file_type()?;
-                if file_type.is_dir() {
-                    dirs.push(entry.path());
-                } else if file_type.is_file() {
-                    self.start_match(&entry.path(), sender.clone(), writer)?;
+        max_depth: Option<usize>,
+        index: Option<Arc<Index>>,
+        sender: &Sender<Vec<u8>>,
+    ) {
+        let skips = self.count_skipped.then(|| Arc::new(SkipCounter::default()));
+        let walker = self
+            .walk_config
+            .walker(dir, max_depth)
+            .threads(self.thread_num)
+            .build_parallel();
+        walker.run(|| {
+            let app = Arc::clone(self);
+            let sender = sender.clone();
+            let index = index.clone();
+            let skips = skips.clone();
+            Box::new(move |entry| {
+                match entry {
+                    Ok(entry) => {
+                        if let Some(ref skips) = skips {
+                            skips.walked(&entry, max_depth);
+                        }
+                        let file_type = entry.file_type();
+                        if file_type.is_some_and(|t| t.is_file()) {
+                            match entry.metadata() {
+                                Ok(meta) if app.may_match(dir, &entry, &meta, index.as_deref()) => {
+                                    app.start_match(entry.into_path(), Some(meta), &sender)
+                                }
+                                Ok(_) => {}
+                                Err(err) => app.report_error(&err),
+                            }
+                        } else if file_type.is_some_and(|t| t.is_dir()) {
+                            app.watch_dir(&entry, max_depth);
+                        }
+                    }
+                    // like GNU grep, a loop doesn't make the search fail
+                    Err(err) => match walk::loop_link(&err) {
+                        Some(link) => app.report_warning(format_args!(
+                            "{}: recursive directory loop",
+                            link.display()
+                        )),
+                        None => app.report_error(&err),
+                    },
                 }
-            }
+                WalkState::Continue
+            })
+        });
+        if let Some(skips) = skips {
+            self.stats.add_ignored(skips.skipped());
         }
-
-        Ok(())
     }
 
-    fn search_files_nonreursively(
+    /// Tells whether `entry` has to be searched, which is always the case
+    /// unless the index of `dir` rules it out.
+    fn may_match(
         &self,
         dir: &str,
-        sender: Sender<Vec<u8>>,
-        writer: &mut BufWriter<StdoutLock>,
-    ) -> Result<()> {
-        let entries = fs::read_dir(dir)?;
-        for entry in entries {
-            let entry = entry?;
-            let file_type = entry.file_type()?;
-            if file_type.is_file() {
-                self.start_match(&entry.path(), sender.clone(), writer)?;
-            }
+        entry: &DirEntry,
+        meta: &Metadata,
+        index: Option<&Index>,
+    ) -> bool {
+        let (index, query) = match (index, &self.index_query) {
+            (Some(index), Some(query)) => (index, query),
+            _ => return true,
+        };
+        let rel = match entry.path().strip_prefix(dir) {
+            Ok(rel) => rel,
+            Err(_) => return true,
+        };
+        let may_match = index.may_match(rel, meta, query);
+        if !may_match {
+            self.stats.add_ignored(1);
         }
-
-        Ok(())
+        may_match
     }
 
+    /// Searches the file at `filepath`, unless the metadata filters rule it
+    /// out. `meta` is what the walker found, if it came from a walk.
     fn start_match(
-        &self,
-        filepath: &PathBuf,
-        sender: Sender<Vec<u8>>,
-        writer: &mut BufWriter<StdoutLock>,
-    ) -> Result<()> {
-        let meta = fs::metadata(filepath)?;
-        let file_size = meta.file_size();
+        self: &Arc<Self>,
+        filepath: PathBuf,
+        meta: Option<Metadata>,
+        sender: &Sender<Vec<u8>>,
+    ) {
+        let meta = match meta.map_or_else(|| fs::metadata(&filepath), Ok) {
+            Ok(meta) => meta,
+            Err(err) => {
+                self.report_path_error(&filepath, &err);
+                return;
+            }
+        };
+        if !self.walk_config.metadata.is_allowed(&meta) {
+            self.stats.add_ignored(1);
+            return;
+        }
+
+        if self.list_files {
+            let mut buf = filepath.into_os_string().into_encoded_bytes();
+            buf.push(if self.null { b'\0' } else { b'\n' });
+            self.matched.store(true, Ordering::Relaxed);
+            sender
+                .send(buf)
+                .expect("Failed to send buffer to main thread: ");
+            return;
+        }
+
+        let file_size = meta.len();
         if file_size >= 5 * K {
-            let p = self.pattern.clone();
-            let f = filepath.clone();
-            rayon::spawn(move || GrepApp::parallel_match_content(p, f, sender));
-        } else {
-            self.match_content(filepath, writer)?;
+            let app = Arc::clone(self);
+            let sender = sender.clone();
+            rayon::spawn(move || {
+                if let Some(buf) = app.match_content(&filepath, file_size) {
+                    sender
+                        .send(buf)
+                        .expect("Failed to send buffer to main thread: ");
+                }
+            });
+        } else if let Some(buf) = self.match_content(&filepath, file_size) {
+            sender
+                .send(buf)
+                .expect("Failed to send buffer to main thread: ");
         }
+    }
 
-        Ok(())
+    /// Matches the file at `filepath`, returning the output to print if any
+    /// line matched. Errors are reported rather than returned so that one bad
+    /// file never stops the whole search.
+    fn match_content(&self, filepath: &Path, file_size: u64) -> Option<Vec<u8>> {
+        self.match_content_again(filepath, file_size).0
     }
 
-    #[inline(always)]
-    fn parallel_match_content(pattern: Regex, filepath: PathBuf, sender: Sender<Vec<u8>>) {
-        GrepApp::parallel_match_content_wrap(pattern, filepath, sender)
-            .expect("Failed to write buffer ");
+    /// Like `match_content`, but also tells whether the file matched the
+    /// previous time it was searched with `--watch`.
+    fn match_content_again(&self, filepath: &Path, file_size: u64) -> (Option<Vec<u8>>, bool) {
+        let buf = match self.match_file(filepath, file_size) {
+            Ok(Some(buf)) => {
+                self.matched.store(true, Ordering::Relaxed);
+                Some(buf)
+            }
+            Ok(None) => None,
+            Err(err) => {
+                self.report_path_error(filepath, &err);
+                None
+            }
+        };
+        let matched_before = match self.watched {
+            Some(ref watched) => watched.lock().unwrap().searched(filepath, buf.is_some()),
+            None => false,
+        };
+
+        (buf, matched_before)
     }
 
-    fn parallel_match_content_wrap(
-        pattern: Regex,
-        filepath: PathBuf,
-        sender: Sender<Vec<u8>>,
-    ) -> Result<()> {
-        let file = File::open(&filepath).expect("Failed to open file: ");
-        let reader = BufReader::new(file);
-        let mut buf = BufWriter::new(Vec::with_capacity(100));
-
-        let mut line_num = 1;
-        let mut matched = false;
-
-        buf.write_fmt(format_args!(
-            "In file \x1b[0;34;1m{}\x1b[0m\n",
-            filepath.as_path().to_str().unwrap()
-        ))?;
-        for line in reader.lines() {
-            let line = match line {
-                Ok(l) => l,
-                Err(_) => return Ok(()),
-            };
-            if let Some(m) = pattern.find(&line) {
-                matched = true;
-                buf.write_fmt(format_args!("\x1b[0;33;1mline {}\x1b[0m: ", line_num))?;
-                let bytes = line.as_bytes();
-                let start = m.start();
-                let end = m.end();
-                let len = bytes.len();
-                if start > 50 {
-                    buf.write_fmt(format_args!(
-                        "\x1b[0;36;1m...\x1b[0m{}",
-                        String::from_utf8_lossy(&bytes[start - 50..start])
-                    ))?;
-                } else {
-                    buf.write_all(&bytes[..start])?;
+    /// Records a walked directory for `--watch` if its files are searched.
+    fn watch_dir(&self, entry: &DirEntry, max_depth: Option<usize>) {
+        if let Some(ref watched) = self.watched {
+            if max_depth.is_none_or(|max_depth| entry.depth() < max_depth) {
+                let levels = max_depth.map(|max_depth| max_depth - entry.depth());
+                watched.lock().unwrap().add_dir(entry.path(), levels);
+            }
+        }
+    }
+
+    /// Waits for changes in the searched directories and searches the files
+    /// which changed again, printing their results as they come. Directories
+    /// which show up where the walk would have gone are walked and watched
+    /// too. Only stops on an error or when killed.
+    fn watch<W: Write>(self: &Arc<Self>, writer: &mut W) -> Result<()> {
+        let watched = self.watched.as_ref().unwrap();
+        let mut changes = Changes::watch(&watched.lock().unwrap())?;
+        loop {
+            for path in changes.next_batch()? {
+                let (path, levels) = {
+                    let mut watched = watched.lock().unwrap();
+                    match watched.searched_as(&path) {
+                        // nothing is walked below a searched file
+                        Some(path) => (path, Some(0)),
+                        None if watched.is_walked(&path) => continue,
+                        None => match watched.walked_as(&path) {
+                            Some((path, levels)) if self.walk_config.accepts(&path) => {
+                                (path, levels)
+                            }
+                            _ => continue,
+                        },
+                    }
+                };
+
+                if levels != Some(0) && path.is_dir() {
+                    if levels.is_none_or(|levels| levels > 1) {
+                        self.search_new_dir(&path, levels.map(|levels| levels - 1), writer)?;
+                        changes.update(&watched.lock().unwrap())?;
+                    }
+                    continue;
                 }
-                buf.write_all(b"\x1b[0;32;1m")?;
-                buf.write_all(&bytes[start..end])?;
-                buf.write_all(b"\x1b[0m")?;
-                if len - end > 50 {
-                    buf.write_fmt(format_args!(
-                        "{}\x1b[0;36;1m...\x1b[0m",
-                        String::from_utf8_lossy(&bytes[end..end + 50])
-                    ))?;
-                } else {
-                    buf.write_all(&bytes[end..])?;
+                let mut out = Vec::new();
+                match fs::metadata(&path) {
+                    Ok(meta) if meta.is_file() && self.walk_config.metadata.is_allowed(&meta) => {
+                        match self.match_content_again(&path, meta.len()) {
+                            (Some(buf), _) => out = buf,
+                            (None, true) => self.printer.unmatched(&mut out, &path)?,
+                            (None, false) => {}
+                        }
+                    }
+                    // removed, filtered out or replaced by something else
+                    // than a file
+                    _ => {
+                        if watched.lock().unwrap().forget_match(&path) {
+                            self.printer.unmatched(&mut out, &path)?;
+                        }
+                    }
                 }
-                buf.write_all(b"\n")?;
+                writer.write_all(&out)?;
             }
-            line_num += 1;
+            writer.flush()?;
+        }
+    }
+
+    /// Searches a directory which was created after the walk, like the walk
+    /// would have, without going through the index.
+    fn search_new_dir<W: Write>(
+        self: &Arc<Self>,
+        dir: &Path,
+        max_depth: Option<usize>,
+        writer: &mut W,
+    ) -> Result<()> {
+        let (sender, receiver) = channel::unbounded();
+        self.search_dir(&dir.to_string_lossy(), max_depth, None, &sender);
+        drop(sender);
+        while let Ok(buf) = receiver.recv() {
+            writer.write_all(&buf)?;
         }
-        buf.write_all(b"\n")?;
+        Ok(())
+    }
 
-        let buf = buf.into_inner()?;
+    fn report_path_error(&self, path: &Path, err: &io::Error) {
+        self.report_error(format_args!("{}: {}", path.display(), err));
+    }
 
-        if matched {
-            sender
-                .send(buf)
-                .expect("Failed to send buffer to main thread: ");
+    /// Reports something which doesn't make the search fail.
+    fn report_warning<E: Display>(&self, warning: E) {
+        if !self.no_messages {
+            eprintln!("grep: warning: {}", warning);
         }
+    }
 
-        Ok(())
+    fn report_error<E: Display>(&self, err: E) {
+        self.errored.store(true, Ordering::Relaxed);
+        self.stats.add_error();
+        if !self.no_messages {
+            eprintln!("grep: {}", err);
+        }
     }
 
-    fn match_content(&self, filepath: &PathBuf, writer: &mut BufWriter<StdoutLock>) -> Result<()> {
-        let file = File::open(&filepath)?;
-        let reader = BufReader::new(file);
-        let mut buf = BufWriter::new(Vec::with_capacity(100));
-
-        let mut line_num = 1;
-        let mut matched = false;
-
-        buf.write_fmt(format_args!(
-            "In file \x1b[0;34;1m{}\x1b[0m\n",
-            filepath.as_path().to_str().unwrap()
-        ))?;
-        for line in reader.lines() {
-            let line = match line {
-                Ok(l) => l,
-                Err(_) => return Ok(()),
-            };
-            if let Some(m) = self.pattern.find(&line) {
-                matched = true;
-                buf.write_fmt(format_args!("\x1b[0;33;1mline {}\x1b[0m: ", line_num))?;
-
-                let bytes = line.as_bytes();
-                let start = m.start();
-                let end = m.end();
-                let len = bytes.len();
-                if start > 50 {
-                    buf.write_fmt(format_args!(
-                        "\x1b[0;36;1m...\x1b[0m{}",
-                        String::from_utf8_lossy(&bytes[start - 50..start])
-                    ))?;
-                } else {
-                    buf.write_all(&bytes[..start])?;
-                }
-                buf.write_all(b"\x1b[0;32;1m")?;
-                buf.write_all(&bytes[start..end])?;
-                buf.write_all(b"\x1b[0m")?;
-                if len - end > 50 {
-                    buf.write_fmt(format_args!(
-                        "{}\x1b[0;36;1m...\x1b[0m",
-                        String::from_utf8_lossy(&bytes[end..end + 50])
-                    ))?;
-                } else {
-                    buf.write_all(&bytes[end..])?;
-                }
-                buf.write_all(b"\n")?;
+    fn match_file(&self, filepath: &Path, file_size: u64) -> Result<Option<Vec<u8>>> {
+        if self.search_archives {
+            if let Some(archive) = Archive::from_path(filepath) {
+                return self.match_archive(filepath, archive);
             }
-            line_num += 1;
         }
-        buf.write_all(b"\n")?;
 
-        let buf = buf.into_inner()?;
+        if self.in_place {
+            let real_path = fs::canonicalize(filepath)?;
+            if !self.edited.lock().unwrap().insert(real_path) {
+                return Ok(None);
+            }
+        }
+        let file = File::open(filepath)?;
+        let compression = if self.search_zip {
+            Compression::from_path(filepath)
+        } else {
+            None
+        };
+        // --dry-run previews what --in-place writes, so it refuses the same
+        // files
+        let edits = self.in_place || self.dry_run;
+        if edits && compression.is_some() {
+            return Err(io::Error::other(
+                "compressed files can't be edited in place",
+            ));
+        }
+        let contents = match compression {
+            Some(compression) => FileBuffer::Heap(compression.decompress(file, file_size)?),
+            None => FileBuffer::open(file, file_size, self.mmap)?,
+        };
 
-        if matched {
-            writer.write_all(&buf)?;
+        if edits && self.searcher.decoding.encoding_of(&contents).is_some() {
+            return Err(io::Error::other(
+                "files which need decoding can't be edited in place",
+            ));
+        }
+        let out = self.match_buffer(filepath, &contents)?;
+        if out.is_some() && self.in_place && !self.searcher.is_binary(&contents) {
+            let template = self.replace.as_deref().unwrap_or_default();
+            let content = replace::replace_content(&self.searcher.matcher, &contents, template);
+            // the file may be memory-mapped, let go of it before replacing it
+            drop(contents);
+            replace::write_atomically(filepath, &content)?;
         }
 
-        Ok(())
+        Ok(out)
+    }
+
+    /// Matches every regular file in an archive, each member is reported as
+    /// `archive:path/in/archive`.
+    fn match_archive(&self, filepath: &Path, archive: Archive) -> Result<Option<Vec<u8>>> {
+        if self.in_place || self.dry_run {
+            return Err(io::Error::other("archives can't be edited in place"));
+        }
+
+        let mut out = Vec::new();
+        archive.for_each_member(File::open(filepath)?, |name, content| {
+            let path = Archive::member_path(filepath, name);
+            if let Some(buf) = self.match_buffer(&path, content)? {
+                out.extend(buf);
+            }
+            Ok(())
+        })?;
+
+        Ok(if out.is_empty() { None } else { Some(out) })
+    }
+
+    /// Matches `contents`, returning the output to print if anything matched.
+    fn match_buffer(&self, filepath: &Path, contents: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.dry_run && !self.searcher.is_binary(contents) {
+            return Ok(self.diff_text(filepath, contents));
+        }
+
+        let mut out = Vec::with_capacity(100);
+        let stats = {
+            let mut sink = self.printer.sink(&mut out);
+            match self.replace {
+                Some(ref template) => {
+                    let sink = ReplaceSink::new(&self.searcher.matcher, template, &mut *sink);
+                    self.searcher.search_slice(filepath, contents, sink)?
+                }
+                None => self.searcher.search_slice(filepath, contents, &mut *sink)?,
+            }
+        };
+        self.stats.add_file(&stats);
+
+        Ok(if stats.matched { Some(out) } else { None })
     }
+
+    /// Returns the diff `--in-place` would apply to `text`, if any.
+    fn diff_text(&self, filepath: &Path, text: &[u8]) -> Option<Vec<u8>> {
+        let template = self.replace.as_deref().unwrap_or_default();
+        let content = replace::replace_content(&self.searcher.matcher, text, template);
+        let stats = FileStats {
+            matched: content[..] != text[..],
+            bytes_searched: text.len() as u64,
+            ..Default::default()
+        };
+        self.stats.add_file(&stats);
+
+        if stats.matched {
+            Some(replace::unified_diff(filepath, text, &content).into_bytes())
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns the default arguments of the config file named by
+/// `GREP_CONFIG_PATH`, or else of `$XDG_CONFIG_HOME/grep/config` or
+/// `~/.config/grep/config` if it exists. The file holds one argument per
+/// line, blank lines and lines starting with `#` are skipped.
+fn config_args() -> Result<Vec<OsString>> {
+    let content = match env::var_os("GREP_CONFIG_PATH") {
+        Some(path) if path.is_empty() => return Ok(Vec::new()),
+        Some(path) => fs::read_to_string(path)?,
+        None => {
+            let config_dir = env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+            let path = match config_dir {
+                Some(dir) => dir.join("grep").join("config"),
+                None => return Ok(Vec::new()),
+            };
+            match fs::read_to_string(path) {
+                Ok(content) => content,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(err) => return Err(err),
+            }
+        }
+    };
+
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(OsString::from)
+        .collect())
+}
+
+/// Reads a list of paths separated by NUL, or by newline if there's no NUL in
+/// it. `-` reads the list from stdin.
+fn read_path_list(path: &str) -> Result<Vec<PathBuf>> {
+    let content = if path == "-" {
+        let mut content = Vec::new();
+        io::stdin().lock().read_to_end(&mut content)?;
+        content
+    } else {
+        fs::read(path)?
+    };
+
+    Ok(walk::split_path_list(&content))
 }
@@ -0,0 +1,382 @@
+use rayon::prelude::*;
+use regex_syntax::hir::literal::{ExtractKind, Extractor};
+use regex_syntax::ParserBuilder;
+use tempfile::NamedTempFile;
+
+use crate::decode::Decoding;
+use crate::matcher::MatcherOptions;
+use crate::walk::WalkConfig;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufReader, BufWriter, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+// bumped whenever what gets indexed changes, so that no stale entry is reused
+const MAGIC: &[u8] = b"GREPIDX2";
+
+/// The trigrams which any match of the patterns has to contain, as a list of
+/// alternatives: a file can only match if it contains every trigram of at
+/// least one alternative, for each list.
+#[derive(Debug, Clone)]
+pub struct TrigramQuery {
+    required: Vec<Vec<Vec<u32>>>,
+}
+
+impl TrigramQuery {
+    /// Returns `None` if the patterns don't have literals long enough to rule
+    /// out any file.
+    pub fn new(patterns: &[String], options: &MatcherOptions) -> Option<TrigramQuery> {
+        let mut required = Vec::new();
+        if options.fixed_strings && !patterns.iter().any(|p| options.ignores_case(p)) {
+            required.push(alternatives(patterns.iter().map(|p| p.as_bytes()))?);
+        } else {
+            // the extractor spells out the cases of letters which ignore case
+            let pattern = options.alternation(patterns);
+            let hir = ParserBuilder::new()
+                .multi_line(true)
+                .crlf(true)
+                .dot_matches_new_line(options.dot_matches_new_line)
+                .build()
+                .parse(&pattern)
+                .ok()?;
+            // every match starts with one of the prefixes and ends with one
+            // of the suffixes, so both have to be in the file
+            for kind in [ExtractKind::Prefix, ExtractKind::Suffix] {
+                let seq = Extractor::new().kind(kind).extract(&hir);
+                if let Some(literals) = seq.literals() {
+                    if let Some(alts) = alternatives(literals.iter().map(|l| l.as_bytes())) {
+                        required.push(alts);
+                    }
+                }
+            }
+        }
+
+        if required.is_empty() {
+            None
+        } else {
+            Some(TrigramQuery { required })
+        }
+    }
+
+    /// Tells whether a file with the sorted `trigrams` could match.
+    pub fn may_match(&self, trigrams: &[u32]) -> bool {
+        self.required.iter().all(|alts| {
+            alts.iter()
+                .any(|alt| alt.iter().all(|t| trigrams.binary_search(t).is_ok()))
+        })
+    }
+}
+
+/// Returns the trigrams of each literal, or `None` if one of them is too
+/// short to have any.
+fn alternatives<'a, I>(literals: I) -> Option<Vec<Vec<u32>>>
+where
+    I: Iterator<Item = &'a [u8]>,
+{
+    literals
+        .map(|literal| {
+            if literal.len() < 3 {
+                None
+            } else {
+                Some(trigrams(literal))
+            }
+        })
+        .collect()
+}
+
+/// Returns the sorted, distinct trigrams of `bytes`.
+fn trigrams(bytes: &[u8]) -> Vec<u32> {
+    let windows = bytes
+        .windows(3)
+        .map(|w| (w[0] as u32) << 16 | (w[1] as u32) << 8 | w[2] as u32);
+    if bytes.len() < 1 << 20 {
+        let mut trigrams: Vec<u32> = windows.collect();
+        trigrams.sort_unstable();
+        trigrams.dedup();
+        return trigrams;
+    }
+
+    // a bitset of all 2^24 trigrams takes less memory than sorting the
+    // windows of a large file
+    let mut seen = vec![0u64; 1 << 18];
+    for t in windows {
+        seen[t as usize >> 6] |= 1 << (t & 63);
+    }
+    (0..1u32 << 24)
+        .filter(|&t| seen[t as usize >> 6] & (1 << (t & 63)) != 0)
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+struct FileEntry {
+    size: u64,
+    mtime: (u64, u32),
+    trigrams: Vec<u32>,
+}
+
+impl FileEntry {
+    fn is_fresh(&self, meta: &Metadata) -> bool {
+        self.size == meta.len() && Some(self.mtime) == mtime(meta)
+    }
+}
+
+fn mtime(meta: &Metadata) -> Option<(u64, u32)> {
+    let since_epoch = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((since_epoch.as_secs(), since_epoch.subsec_nanos()))
+}
+
+/// Trigrams of every file under a directory, keyed by the path relative to
+/// it, so that searches can skip the files which can't match.
+///
+/// Files which changed since the index was built or which aren't in it are
+/// never skipped, so a stale index only makes a search slower.
+#[derive(Debug, Default)]
+pub struct Index {
+    files: HashMap<String, FileEntry>,
+}
+
+/// Counts of what `Index::build` did.
+#[derive(Debug, Default)]
+pub struct BuildStats {
+    pub files: usize,
+    pub updated: usize,
+}
+
+impl Index {
+    /// Where the index of `root` is kept: `$XDG_CACHE_HOME/grep/index`, or
+    /// `~/.cache/grep/index`, in a file named after a hash of the path.
+    pub fn path(root: &Path) -> Result<PathBuf> {
+        let cache_dir = env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .unwrap_or_else(env::temp_dir);
+        let root = fs::canonicalize(root)?;
+        // FNV-1a, which unlike the std hasher is stable between releases
+        let hash = root
+            .as_os_str()
+            .as_encoded_bytes()
+            .iter()
+            .fold(0xcbf29ce484222325u64, |hash, &b| {
+                (hash ^ b as u64).wrapping_mul(0x100000001b3)
+            });
+
+        Ok(cache_dir
+            .join("grep")
+            .join("index")
+            .join(format!("{:016x}", hash)))
+    }
+
+    /// Loads the index of `root`, or returns `None` if it was never built.
+    pub fn load(root: &Path) -> Result<Option<Index>> {
+        let file = match File::open(Index::path(root)?) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut reader = BufReader::new(file);
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid_data("not a grep index"));
+        }
+
+        let mut index = Index::default();
+        for _ in 0..read_u32(&mut reader)? {
+            let mut path = vec![0; read_u32(&mut reader)? as usize];
+            reader.read_exact(&mut path)?;
+            let path = String::from_utf8(path).map_err(invalid_data)?;
+            let size = read_u64(&mut reader)?;
+            let mtime = (read_u64(&mut reader)?, read_u32(&mut reader)?);
+            let trigrams = (0..read_u32(&mut reader)?)
+                .map(|_| read_u32(&mut reader))
+                .collect::<Result<_>>()?;
+            index.files.insert(
+                path,
+                FileEntry {
+                    size,
+                    mtime,
+                    trigrams,
+                },
+            );
+        }
+
+        Ok(Some(index))
+    }
+
+    /// Indexes every file the walker finds under `root`, reusing the entries
+    /// of the previous index whose size and mtime haven't changed.
+    ///
+    /// Files with a UTF-16 byte order mark are left out: they're decoded
+    /// before they're searched, so the trigrams of their bytes say nothing.
+    pub fn build(root: &Path, walk_config: &WalkConfig) -> Result<BuildStats> {
+        // an index which can't be read, say of an older format, is rebuilt
+        let old = Index::load(root).ok().flatten().unwrap_or_default();
+
+        let root_str = root.to_string_lossy();
+        let mut paths = Vec::new();
+        for entry in walk_config.walker(&root_str, walk_config.max_depth).build() {
+            // entries which can't be read are left out, they're always searched
+            match entry {
+                Ok(entry) if entry.file_type().is_some_and(|t| t.is_file()) => {
+                    paths.push(entry.into_path())
+                }
+                _ => {}
+            }
+        }
+
+        let entries: Vec<(String, FileEntry, bool)> = paths
+            .par_iter()
+            .filter_map(|path| {
+                // paths which aren't UTF-8 are left out, they're always searched
+                let rel = path.strip_prefix(root).ok()?.to_str()?.to_string();
+                let meta = fs::metadata(path).ok()?;
+                if let Some(entry) = old.files.get(&rel).filter(|e| e.is_fresh(&meta)) {
+                    return Some((rel, entry.clone(), false));
+                }
+                let content = fs::read(path).ok()?;
+                if Decoding::Auto.encoding_of(&content).is_some() {
+                    return None;
+                }
+                let entry = FileEntry {
+                    size: meta.len(),
+                    mtime: mtime(&meta)?,
+                    trigrams: trigrams(&content),
+                };
+                Some((rel, entry, true))
+            })
+            .collect();
+
+        let stats = BuildStats {
+            files: entries.len(),
+            updated: entries.iter().filter(|(_, _, updated)| *updated).count(),
+        };
+        let index = Index {
+            files: entries
+                .into_iter()
+                .map(|(rel, entry, _)| (rel, entry))
+                .collect(),
+        };
+        index.save(root)?;
+
+        Ok(stats)
+    }
+
+    fn save(&self, root: &Path) -> Result<()> {
+        let path = Index::path(root)?;
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir)?;
+
+        let tmp = NamedTempFile::new_in(dir)?;
+        let mut writer = BufWriter::new(tmp);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(self.files.len() as u32).to_le_bytes())?;
+        for (rel, entry) in &self.files {
+            writer.write_all(&(rel.len() as u32).to_le_bytes())?;
+            writer.write_all(rel.as_bytes())?;
+            writer.write_all(&entry.size.to_le_bytes())?;
+            writer.write_all(&entry.mtime.0.to_le_bytes())?;
+            writer.write_all(&entry.mtime.1.to_le_bytes())?;
+            writer.write_all(&(entry.trigrams.len() as u32).to_le_bytes())?;
+            for trigram in &entry.trigrams {
+                writer.write_all(&trigram.to_le_bytes())?;
+            }
+        }
+        let tmp = writer.into_inner().map_err(|err| err.into_error())?;
+        tmp.persist(&path).map_err(|err| err.error)?;
+
+        Ok(())
+    }
+
+    /// Tells whether the file at `rel`, relative to the indexed directory,
+    /// has to be searched.
+    pub fn may_match(&self, rel: &Path, meta: &Metadata, query: &TrigramQuery) -> bool {
+        let entry = match rel.to_str().and_then(|rel| self.files.get(rel)) {
+            Some(entry) if entry.is_fresh(meta) => entry,
+            _ => return true,
+        };
+        query.may_match(&entry.trigrams)
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::index::*;
+    use crate::matcher::Case;
+
+    #[test]
+    fn narrow_by_trigrams() {
+        let query = |patterns: &[&str], fixed_strings| {
+            let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+            let options = MatcherOptions {
+                fixed_strings,
+                ..Default::default()
+            };
+            TrigramQuery::new(&patterns, &options)
+        };
+
+        let q = query(&[r"foo\w+bar"], false).unwrap();
+        assert!(q.may_match(&trigrams(b"xx foo_bar")));
+        assert!(!q.may_match(&trigrams(b"xx foo_baz")));
+        assert!(!q.may_match(&trigrams(b"xx fo_bar")));
+
+        let q = query(&["hello", "world"], true).unwrap();
+        assert!(q.may_match(&trigrams(b"a world")));
+        assert!(!q.may_match(&trigrams(b"a word")));
+
+        // too short or unbounded literals can't rule anything out
+        assert!(query(&["ab"], false).is_none());
+        assert!(query(&[r"\w+"], false).is_none());
+        assert!(query(&["hello", "hi"], true).is_none());
+
+        let options = MatcherOptions {
+            fixed_strings: true,
+            case: Case::Insensitive,
+            ..Default::default()
+        };
+        let q = TrigramQuery::new(&["hello".to_string()], &options).unwrap();
+        assert!(q.may_match(&trigrams(b"say HeLLo")));
+        assert!(!q.may_match(&trigrams(b"say help")));
+    }
+
+    #[test]
+    fn never_rule_out_decoded_files() {
+        let cache = tempfile::tempdir().unwrap();
+        env::set_var("XDG_CACHE_HOME", cache.path());
+        let dir = tempfile::tempdir().unwrap();
+        let mut utf16 = vec![0xff, 0xfe];
+        utf16.extend("say hello\n".encode_utf16().flat_map(u16::to_le_bytes));
+        fs::write(dir.path().join("utf16.txt"), utf16).unwrap();
+        fs::write(dir.path().join("plain.txt"), "say goodbye\n").unwrap();
+
+        let stats = Index::build(dir.path(), &WalkConfig::default()).unwrap();
+        assert_eq!(stats.files, 1);
+        let index = Index::load(dir.path()).unwrap().unwrap();
+        let query = TrigramQuery::new(&["hello".to_string()], &MatcherOptions::default()).unwrap();
+        for (name, may_match) in [("utf16.txt", true), ("plain.txt", false)] {
+            let meta = fs::metadata(dir.path().join(name)).unwrap();
+            assert_eq!(index.may_match(Path::new(name), &meta, &query), may_match);
+        }
+    }
+}
@@ -0,0 +1,18 @@
+//! The search engine behind the `grep` binary.
+//!
+//! A [`Searcher`](searcher::Searcher) finds the matching lines of a buffer,
+//! with their context, and hands them to a [`Sink`](searcher::Sink). The
+//! printers of the command line tool are sinks too, see [`printer`].
+
+pub mod archive;
+pub mod buffer;
+pub mod decode;
+pub mod fuzzy;
+pub mod index;
+pub mod matcher;
+pub mod printer;
+pub mod replace;
+pub mod searcher;
+pub mod stats;
+pub mod walk;
+pub mod watch;
@@ -2,11 +2,12 @@ mod grep_app;
 
 use grep_app::GrepApp;
 use std::io::Result;
+use std::process;
 
 fn main() -> Result<()> {
     let mut app = GrepApp::new();
     app.get_args();
-    app.run()?;
+    let status = app.run()?;
 
-    Ok(())
+    process::exit(status);
 }
@@ -0,0 +1,524 @@
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder, MatchKind};
+use memchr::memchr;
+use regex::bytes::{Regex, RegexBuilder};
+use regex_syntax::ast::{self, parse::Parser};
+use regex_syntax::hir::{
+    Capture, Class, ClassBytes, ClassBytesRange, ClassUnicode, ClassUnicodeRange, Hir, HirKind,
+    Repetition,
+};
+use regex_syntax::ParserBuilder;
+use thiserror::Error;
+
+use crate::fuzzy::Fuzzy;
+
+/// Matches a line against one or more patterns.
+///
+/// Regular expressions are combined into a single alternation, while fixed
+/// strings (`-F`) go to an Aho-Corasick automaton so that a long list of
+/// literals never turns into a giant regex. `-P` swaps in a backtracking
+/// engine for patterns which need look-around or backreferences, and
+/// `--fuzzy` matches literals within an edit distance.
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    Regex(Regex),
+    Literals(Box<AhoCorasick>),
+    #[cfg(feature = "backtrack")]
+    Backtrack(Box<Backtrack>),
+    Fuzzy(Fuzzy),
+}
+
+#[derive(Debug, Error)]
+pub enum MatcherError {
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+    #[cfg(feature = "backtrack")]
+    #[error(transparent)]
+    Backtrack(#[from] fancy_regex::Error),
+    #[error("{0:?} uses look-around or backreferences, which need -P")]
+    NeedsBacktrack(String),
+    #[cfg(not(feature = "backtrack"))]
+    #[error("-P is unavailable, grep was built without the `backtrack` feature")]
+    BacktrackUnavailable,
+    #[error("--fuzzy {0} needs every pattern to be longer than {0} bytes")]
+    FuzzyPatternTooShort(usize),
+}
+
+/// Flags which change how patterns are interpreted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MatcherOptions {
+    /// Interpret the patterns as fixed strings (`-F`).
+    pub fixed_strings: bool,
+    /// Let `.` match `\n` too, only useful with `--multiline`.
+    pub dot_matches_new_line: bool,
+    /// Matches may span lines (`--multiline`).
+    pub multiline: bool,
+    /// Use the backtracking engine (`-P`).
+    pub backtrack: bool,
+    /// Match the patterns as literals within this edit distance (`--fuzzy`).
+    pub fuzzy: Option<usize>,
+    pub case: Case,
+}
+
+/// Whether patterns match regardless of letter case.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Case {
+    #[default]
+    Sensitive,
+    /// Every pattern ignores case (`-i/--ignore-case`).
+    Insensitive,
+    /// Only patterns without an uppercase letter ignore case
+    /// (`-S/--smart-case`).
+    Smart,
+}
+
+impl MatcherOptions {
+    /// Tells whether `pattern` matches regardless of case.
+    pub fn ignores_case(&self, pattern: &str) -> bool {
+        match self.case {
+            Case::Sensitive => false,
+            Case::Insensitive => true,
+            Case::Smart => !has_uppercase(pattern, self.fixed_strings),
+        }
+    }
+
+    /// Combines `patterns` into a single regex, with fixed strings escaped
+    /// and the patterns which ignore case in a `(?i:...)` group.
+    pub fn alternation(&self, patterns: &[String]) -> String {
+        let group = |pattern: &String| {
+            let flags = if self.ignores_case(pattern) {
+                "?i:"
+            } else {
+                "?:"
+            };
+            if self.fixed_strings {
+                format!("({}{})", flags, regex::escape(pattern))
+            } else {
+                format!("({}{})", flags, pattern)
+            }
+        };
+        match patterns {
+            [pattern] if !self.fixed_strings && !self.ignores_case(pattern) => pattern.clone(),
+            _ => patterns.iter().map(group).collect::<Vec<_>>().join("|"),
+        }
+    }
+}
+
+impl Matcher {
+    pub fn new(patterns: &[String], options: &MatcherOptions) -> Result<Matcher, MatcherError> {
+        if let Some(max_distance) = options.fuzzy {
+            return Fuzzy::new(patterns, max_distance, |p| options.ignores_case(p))
+                .map(Matcher::Fuzzy)
+                .ok_or(MatcherError::FuzzyPatternTooShort(max_distance));
+        }
+        // literals which ignore case go through the regex engine, which folds
+        // more than ASCII
+        if options.fixed_strings && !patterns.iter().any(|p| options.ignores_case(p)) {
+            let ac = AhoCorasickBuilder::new()
+                .match_kind(MatchKind::LeftmostLongest)
+                .auto_configure(patterns)
+                .build(patterns);
+            return Ok(Matcher::Literals(Box::new(ac)));
+        }
+
+        let mut pattern = options.alternation(patterns);
+        if options.backtrack {
+            return Matcher::backtrack(&pattern, options);
+        }
+        if !options.multiline {
+            // a match which could run past the end of its line would be found
+            // again from every line it covers, the parse error of a pattern
+            // that doesn't parse is left to the regex builder
+            let hir = ParserBuilder::new()
+                .multi_line(true)
+                .crlf(true)
+                .dot_matches_new_line(options.dot_matches_new_line)
+                .build()
+                .parse(&pattern);
+            if let Ok(hir) = hir {
+                pattern = strip_newline(&hir).to_string();
+            }
+        }
+
+        // `^` and `$` have to match at line boundaries since whole buffers
+        // are searched at once
+        let regex = RegexBuilder::new(&pattern)
+            .multi_line(true)
+            .crlf(true)
+            .dot_matches_new_line(options.dot_matches_new_line)
+            .build();
+
+        match regex {
+            Ok(regex) => Ok(Matcher::Regex(regex)),
+            Err(err) => match patterns.iter().find(|p| needs_backtrack(p)) {
+                Some(pattern) => Err(MatcherError::NeedsBacktrack(pattern.clone())),
+                None => Err(err.into()),
+            },
+        }
+    }
+
+    #[cfg(feature = "backtrack")]
+    fn backtrack(pattern: &str, options: &MatcherOptions) -> Result<Matcher, MatcherError> {
+        let flags = if options.dot_matches_new_line {
+            "(?ms)"
+        } else {
+            "(?m)"
+        };
+        let regex = fancy_regex::Regex::new(&format!("{}(?:{})", flags, pattern))?;
+
+        Ok(Matcher::Backtrack(Box::new(Backtrack {
+            regex,
+            multiline: options.multiline,
+        })))
+    }
+
+    #[cfg(not(feature = "backtrack"))]
+    fn backtrack(_pattern: &str, _options: &MatcherOptions) -> Result<Matcher, MatcherError> {
+        Err(MatcherError::BacktrackUnavailable)
+    }
+
+    /// Returns the byte ranges of all non-overlapping matches in `line`.
+    pub fn find_iter(&self, line: &[u8]) -> Vec<(usize, usize)> {
+        match self {
+            Matcher::Regex(re) => re.find_iter(line).map(|m| (m.start(), m.end())).collect(),
+            Matcher::Literals(ac) => ac.find_iter(line).map(|m| (m.start(), m.end())).collect(),
+            #[cfg(feature = "backtrack")]
+            Matcher::Backtrack(bt) => bt.find_iter(line),
+            Matcher::Fuzzy(fuzzy) => fuzzy.find_iter(line),
+        }
+    }
+
+    /// Returns the edit distance of each of the `submatches` of `line`, which
+    /// is only known with `--fuzzy`.
+    pub fn distances(&self, line: &[u8], submatches: &[(usize, usize)]) -> Vec<usize> {
+        match self {
+            Matcher::Fuzzy(fuzzy) => submatches
+                .iter()
+                .map(|&(start, end)| fuzzy.distance(&line[start..end]))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Replaces every match in `line` with `template`, in which `$1` or
+    /// `${name}` expand to capture groups. Returns the new line and the byte
+    /// ranges of the replacements within it.
+    pub fn replace(&self, line: &[u8], template: &[u8]) -> (Vec<u8>, Vec<(usize, usize)>) {
+        let mut replaced = Vec::with_capacity(line.len());
+        let mut spans = Vec::new();
+        let mut last = 0;
+        match self {
+            Matcher::Regex(re) => {
+                for caps in re.captures_iter(line) {
+                    let m = caps.get(0).unwrap();
+                    replaced.extend_from_slice(&line[last..m.start()]);
+                    let start = replaced.len();
+                    caps.expand(template, &mut replaced);
+                    spans.push((start, replaced.len()));
+                    last = m.end();
+                }
+            }
+            Matcher::Literals(_) | Matcher::Fuzzy(_) => {
+                for (m_start, m_end) in self.find_iter(line) {
+                    replaced.extend_from_slice(&line[last..m_start]);
+                    let start = replaced.len();
+                    replaced.extend_from_slice(template);
+                    spans.push((start, replaced.len()));
+                    last = m_end;
+                }
+            }
+            #[cfg(feature = "backtrack")]
+            Matcher::Backtrack(bt) => {
+                let template = String::from_utf8_lossy(template);
+                let mut expanded = String::new();
+                for (offset, text) in utf8_chunks(line) {
+                    for caps in bt.regex.captures_iter(text).filter_map(Result::ok) {
+                        let m = caps.get(0).unwrap();
+                        replaced.extend_from_slice(&line[last..offset + m.start()]);
+                        expanded.clear();
+                        caps.expand(&template, &mut expanded);
+                        let start = replaced.len();
+                        replaced.extend_from_slice(expanded.as_bytes());
+                        spans.push((start, replaced.len()));
+                        last = offset + m.end();
+                    }
+                }
+            }
+        }
+        replaced.extend_from_slice(&line[last..]);
+
+        (replaced, spans)
+    }
+
+    /// Returns the byte range of the first match in `haystack` which starts at
+    /// or after `start`.
+    pub fn find_at(&self, haystack: &[u8], start: usize) -> Option<(usize, usize)> {
+        match self {
+            Matcher::Regex(re) => re.find_at(haystack, start).map(|m| (m.start(), m.end())),
+            Matcher::Literals(ac) => ac
+                .find(&haystack[start..])
+                .map(|m| (start + m.start(), start + m.end())),
+            #[cfg(feature = "backtrack")]
+            Matcher::Backtrack(bt) => bt.find_at(haystack, start),
+            Matcher::Fuzzy(fuzzy) => find_by_line(haystack, start, |line| {
+                fuzzy.find(line, 0).map(|(start, end, _)| (start, end))
+            }),
+        }
+    }
+}
+
+/// Runs `find` on one line of `haystack` after the other, starting with the
+/// one `start` is in, for matchers which are expensive to run over a whole
+/// buffer at once. Line terminators aren't passed to `find`.
+fn find_by_line<F>(haystack: &[u8], start: usize, find: F) -> Option<(usize, usize)>
+where
+    F: Fn(&[u8]) -> Option<(usize, usize)>,
+{
+    let mut line_start = start;
+    loop {
+        let line_end =
+            memchr(b'\n', &haystack[line_start..]).map_or(haystack.len(), |i| line_start + i);
+        let mut line = &haystack[line_start..line_end];
+        if line.last() == Some(&b'\r') {
+            line = &line[..line.len() - 1];
+        }
+        if let Some((s, e)) = find(line) {
+            return Some((line_start + s, line_start + e));
+        }
+        if line_end == haystack.len() {
+            return None;
+        }
+        line_start = line_end + 1;
+    }
+}
+
+/// Returns `hir` without `\n` in any of its classes or literals, so that
+/// none of its matches spans lines.
+fn strip_newline(hir: &Hir) -> Hir {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => hir.clone(),
+        HirKind::Literal(literal) if literal.0.contains(&b'\n') => Hir::fail(),
+        HirKind::Literal(_) => hir.clone(),
+        HirKind::Class(Class::Unicode(class)) => {
+            let mut class = class.clone();
+            class.difference(&ClassUnicode::new([ClassUnicodeRange::new('\n', '\n')]));
+            Hir::class(Class::Unicode(class))
+        }
+        HirKind::Class(Class::Bytes(class)) => {
+            let mut class = class.clone();
+            class.difference(&ClassBytes::new([ClassBytesRange::new(b'\n', b'\n')]));
+            Hir::class(Class::Bytes(class))
+        }
+        HirKind::Repetition(repetition) => Hir::repetition(Repetition {
+            sub: Box::new(strip_newline(&repetition.sub)),
+            ..repetition.clone()
+        }),
+        HirKind::Capture(capture) => Hir::capture(Capture {
+            sub: Box::new(strip_newline(&capture.sub)),
+            ..capture.clone()
+        }),
+        HirKind::Concat(subs) => Hir::concat(subs.iter().map(strip_newline).collect()),
+        HirKind::Alternation(subs) => Hir::alternation(subs.iter().map(strip_newline).collect()),
+    }
+}
+
+/// Tells whether `pattern` failed to parse only because it uses syntax which
+/// the default engine doesn't support.
+fn needs_backtrack(pattern: &str) -> bool {
+    match Parser::new().parse(pattern) {
+        Ok(_) => false,
+        Err(err) => matches!(
+            err.kind(),
+            ast::ErrorKind::UnsupportedLookAround | ast::ErrorKind::UnsupportedBackreference
+        ),
+    }
+}
+
+/// Tells whether `pattern` has an uppercase letter, not counting the ones in
+/// escapes like `\S` or `\p{Greek}`.
+fn has_uppercase(pattern: &str, fixed_strings: bool) -> bool {
+    if fixed_strings {
+        return pattern.chars().any(char::is_uppercase);
+    }
+    match Parser::new().parse(pattern) {
+        // the visitor stops at the first uppercase letter
+        Ok(ast) => ast::visit(&ast, Uppercase).is_err(),
+        // syntax only -P understands, go by the letters outside escapes
+        Err(_) => {
+            let mut chars = pattern.chars();
+            while let Some(c) = chars.next() {
+                if c == '\\' {
+                    chars.next();
+                } else if c.is_uppercase() {
+                    return true;
+                }
+            }
+            false
+        }
+    }
+}
+
+struct Uppercase;
+
+impl ast::Visitor for Uppercase {
+    type Output = ();
+    type Err = ();
+
+    fn finish(self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn visit_pre(&mut self, ast: &ast::Ast) -> Result<(), ()> {
+        match ast {
+            ast::Ast::Literal(lit) if lit.c.is_uppercase() => Err(()),
+            _ => Ok(()),
+        }
+    }
+
+    fn visit_class_set_item_pre(&mut self, item: &ast::ClassSetItem) -> Result<(), ()> {
+        match item {
+            ast::ClassSetItem::Literal(lit) if lit.c.is_uppercase() => Err(()),
+            ast::ClassSetItem::Range(range)
+                if range.start.c.is_uppercase() || range.end.c.is_uppercase() =>
+            {
+                Err(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A backtracking regex, which only searches valid UTF-8.
+#[cfg(feature = "backtrack")]
+#[derive(Debug, Clone)]
+pub struct Backtrack {
+    regex: fancy_regex::Regex,
+    multiline: bool,
+}
+
+#[cfg(feature = "backtrack")]
+impl Backtrack {
+    /// Matches which hit the backtracking limit are treated as no match.
+    fn find_iter(&self, text: &[u8]) -> Vec<(usize, usize)> {
+        utf8_chunks(text)
+            .flat_map(|(offset, text)| {
+                self.regex
+                    .find_iter(text)
+                    .filter_map(Result::ok)
+                    .map(move |m| (offset + m.start(), offset + m.end()))
+            })
+            .collect()
+    }
+
+    fn find_first(&self, text: &[u8]) -> Option<(usize, usize)> {
+        utf8_chunks(text).find_map(|(offset, text)| match self.regex.find(text) {
+            Ok(Some(m)) => Some((offset + m.start(), offset + m.end())),
+            _ => None,
+        })
+    }
+
+    fn find_at(&self, haystack: &[u8], start: usize) -> Option<(usize, usize)> {
+        if self.multiline {
+            return self
+                .find_first(&haystack[start..])
+                .map(|(s, e)| (start + s, start + e));
+        }
+
+        // go line by line, or every call would validate the rest of the
+        // buffer as UTF-8 again
+        find_by_line(haystack, start, |line| self.find_first(line))
+    }
+}
+
+/// Splits `text` into runs of valid UTF-8 and their offsets, dropping the
+/// invalid bytes in between.
+#[cfg(feature = "backtrack")]
+fn utf8_chunks(text: &[u8]) -> impl Iterator<Item = (usize, &str)> {
+    let base = text.as_ptr() as usize;
+    text.utf8_chunks()
+        .map(move |chunk| (chunk.valid().as_ptr() as usize - base, chunk.valid()))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::matcher::*;
+
+    #[test]
+    fn match_multiple_patterns() {
+        let patterns = vec!["fo+".to_string(), "ba[rz]".to_string()];
+        let matcher = Matcher::new(&patterns, &MatcherOptions::default()).unwrap();
+        assert_eq!(matcher.find_at(b"xx foo", 0), Some((3, 6)));
+        assert_eq!(matcher.find_at(b"a baz", 0), Some((2, 5)));
+        assert_eq!(matcher.find_at(b"qux", 0), None);
+
+        let patterns = vec![r"(?P<key>\w+)=(\w+)".to_string()];
+        let matcher = Matcher::new(&patterns, &MatcherOptions::default()).unwrap();
+        let (replaced, spans) = matcher.replace(b"a=1, b=2", b"$2:${key}");
+        assert_eq!(replaced, b"1:a, 2:b");
+        assert_eq!(spans, vec![(0, 3), (5, 8)]);
+
+        let patterns = vec!["a.b".to_string(), "ab".to_string(), "abc".to_string()];
+        let matcher = Matcher::new(
+            &patterns,
+            &MatcherOptions {
+                fixed_strings: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(matcher.find_at(b"axb", 0), None);
+        assert_eq!(matcher.find_at(b"1a.b", 0), Some((1, 4)));
+        assert_eq!(matcher.find_at(b"abcd", 0), Some((0, 3)));
+    }
+
+    #[test]
+    fn suggest_backtracking_engine() {
+        let patterns = vec![r"foo(?=bar)".to_string()];
+        let err = Matcher::new(&patterns, &MatcherOptions::default()).unwrap_err();
+        assert!(matches!(err, MatcherError::NeedsBacktrack(_)));
+
+        #[cfg(feature = "backtrack")]
+        {
+            let options = MatcherOptions {
+                backtrack: true,
+                ..Default::default()
+            };
+            let matcher = Matcher::new(&patterns, &options).unwrap();
+            assert_eq!(
+                matcher.find_at(b"foo\nfoobaz\n\xfffoobar", 0),
+                Some((12, 15))
+            );
+
+            let patterns = vec![r"(\w)\1".to_string()];
+            let matcher = Matcher::new(&patterns, &options).unwrap();
+            assert_eq!(matcher.find_iter(b"abba \xff cc"), vec![(1, 3), (7, 9)]);
+            let (replaced, _) = matcher.replace(b"abba", b"<$1>");
+            assert_eq!(replaced, b"a<b>a");
+        }
+    }
+
+    #[test]
+    fn smart_case_per_pattern() {
+        let options = MatcherOptions {
+            case: Case::Smart,
+            ..Default::default()
+        };
+        assert!(options.ignores_case(r"\w+\S\p{Greek}"));
+        assert!(!options.ignores_case("[A-Z]x"));
+        assert!(!options.ignores_case(r"(?<=a)B"));
+
+        let patterns = vec!["foo".to_string(), "Bar".to_string()];
+        let matcher = Matcher::new(&patterns, &options).unwrap();
+        assert_eq!(matcher.find_iter(b"FOO bar Bar"), vec![(0, 3), (8, 11)]);
+
+        let options = MatcherOptions {
+            fixed_strings: true,
+            ..options
+        };
+        let patterns = vec!["a.b".to_string(), "Bar".to_string()];
+        let matcher = Matcher::new(&patterns, &options).unwrap();
+        assert_eq!(
+            matcher.find_iter(b"A.B axb bar Bar"),
+            vec![(0, 3), (12, 15)]
+        );
+    }
+}
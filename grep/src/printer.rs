@@ -0,0 +1,553 @@
+use serde_json::{json, Value};
+
+use crate::buffer::Line;
+use crate::searcher::Sink;
+use crate::stats::{FileStats, Totals};
+
+use std::fmt::Debug;
+use std::io::{Result, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Turns search events into output.
+///
+/// The output of a file is collected in a buffer of its own by the sink the
+/// printer hands out, which is only printed if something in the file matched.
+/// The summary is written once every file has been searched.
+pub trait Printer: Debug + Send + Sync {
+    fn sink<'a>(&'a self, out: &'a mut Vec<u8>) -> Box<dyn Sink + 'a>;
+    /// A file which matched before doesn't anymore, only with `--watch`.
+    fn unmatched(&self, out: &mut Vec<u8>, path: &Path) -> Result<()>;
+    fn summary(&self, _out: &mut dyn Write, _totals: &Totals, _elapsed: Duration) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// SGR parameters of the colors the standard printer uses, an empty string
+/// leaves that part uncolored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Colors {
+    pub path: String,
+    pub line_num: String,
+    pub matched: String,
+    pub context: String,
+    pub separator: String,
+}
+
+impl Default for Colors {
+    fn default() -> Self {
+        Colors {
+            path: "0;34;1".to_string(),
+            line_num: "0;33;1".to_string(),
+            matched: "0;32;1".to_string(),
+            context: String::new(),
+            separator: "0;36;1".to_string(),
+        }
+    }
+}
+
+impl Colors {
+    /// Overrides the colors given in a `GREP_COLORS` spec such as
+    /// `ms=01;31:fn=35:ln=32`. `fn`, `ln`, `ms`/`mt`, `cx` and `se` are
+    /// understood, every other capability is ignored.
+    pub fn with_spec(mut self, spec: &str) -> Colors {
+        for capability in spec.split(':') {
+            let (name, value) = match capability.split_once('=') {
+                Some((name, value)) => (name, value.to_string()),
+                None => continue,
+            };
+            match name {
+                "fn" => self.path = value,
+                "ln" => self.line_num = value,
+                "ms" | "mt" => self.matched = value,
+                "cx" => self.context = value,
+                "se" => self.separator = value,
+                _ => {}
+            }
+        }
+        self
+    }
+}
+
+/// The human readable format, with ANSI colors unless `colors` is `None`.
+#[derive(Debug, Default)]
+pub struct StandardPrinter {
+    colors: Option<Colors>,
+    byte_offset: bool,
+    column: bool,
+}
+
+impl StandardPrinter {
+    pub fn new(colors: Option<Colors>) -> Self {
+        StandardPrinter {
+            colors,
+            ..Default::default()
+        }
+    }
+
+    /// Prints the byte offset of every line within its file (`-b`).
+    pub fn byte_offset(mut self, yes: bool) -> Self {
+        self.byte_offset = yes;
+        self
+    }
+
+    /// Prints the 1-based byte column of the first match of every matched
+    /// line (`--column`).
+    pub fn column(mut self, yes: bool) -> Self {
+        self.column = yes;
+        self
+    }
+}
+
+impl Printer for StandardPrinter {
+    fn sink<'a>(&'a self, out: &'a mut Vec<u8>) -> Box<dyn Sink + 'a> {
+        Box::new(StandardSink { printer: self, out })
+    }
+
+    fn unmatched(&self, out: &mut Vec<u8>, path: &Path) -> Result<()> {
+        out.write_all(b"No more matches in file ")?;
+        paint(
+            out,
+            self.colors.as_ref(),
+            |c| &c.path,
+            path.display().to_string().as_bytes(),
+        );
+        out.write_all(b"\n\n")
+    }
+}
+
+struct StandardSink<'a> {
+    printer: &'a StandardPrinter,
+    out: &'a mut Vec<u8>,
+}
+
+impl StandardSink<'_> {
+    fn paint<F>(&mut self, color: F, text: &[u8])
+    where
+        F: Fn(&Colors) -> &str,
+    {
+        paint(self.out, self.printer.colors.as_ref(), color, text);
+    }
+
+    fn line_num(&mut self, line: &Line, column: Option<usize>, separator: &str) {
+        let mut line_num = format!("line {}", line.line_num);
+        if let Some(column) = column {
+            line_num.push_str(&format!(", column {}", column));
+        }
+        if self.printer.byte_offset {
+            line_num.push_str(&format!(", byte {}", line.offset));
+        }
+        self.paint(|c| &c.line_num, line_num.as_bytes());
+        self.out.extend_from_slice(separator.as_bytes());
+    }
+}
+
+impl Sink for StandardSink<'_> {
+    fn begin(&mut self, path: &Path) -> Result<()> {
+        self.out.write_all(b"In file ")?;
+        self.paint(|c| &c.path, path.display().to_string().as_bytes());
+        self.out.write_all(b"\n")
+    }
+
+    fn matched(&mut self, _path: &Path, line: &Line) -> Result<bool> {
+        let column = self.printer.column.then(|| line.submatches[0].0 + 1);
+        match line.distances.first() {
+            Some(distance) => self.line_num(line, column, &format!(" (distance {}): ", distance)),
+            None => self.line_num(line, column, ": "),
+        }
+
+        let bytes = line.bytes;
+        let (start, end) = line.submatches[0];
+        let len = bytes.len();
+        if start > 50 {
+            self.paint(|c| &c.separator, b"...");
+            self.out
+                .write_all(String::from_utf8_lossy(&bytes[start - 50..start]).as_bytes())?;
+        } else {
+            self.out
+                .write_all(String::from_utf8_lossy(&bytes[..start]).as_bytes())?;
+        }
+        let matched = String::from_utf8_lossy(&bytes[start..end]);
+        self.paint(|c| &c.matched, matched.as_bytes());
+        if len - end > 50 {
+            self.out
+                .write_all(String::from_utf8_lossy(&bytes[end..end + 50]).as_bytes())?;
+            self.paint(|c| &c.separator, b"...");
+        } else {
+            self.out
+                .write_all(String::from_utf8_lossy(&bytes[end..]).as_bytes())?;
+        }
+        self.out.write_all(b"\n")?;
+        Ok(true)
+    }
+
+    fn context(&mut self, _path: &Path, line: &Line) -> Result<bool> {
+        self.line_num(line, None, "- ");
+        let text = String::from_utf8_lossy(line.bytes);
+        self.paint(|c| &c.context, text.as_bytes());
+        self.out.write_all(b"\n")?;
+        Ok(true)
+    }
+
+    fn context_break(&mut self) -> Result<()> {
+        self.paint(|c| &c.separator, b"--");
+        self.out.write_all(b"\n")
+    }
+
+    fn binary_matched(&mut self, path: &Path, _stats: &FileStats) -> Result<()> {
+        self.out.write_all(b"Binary file ")?;
+        self.paint(|c| &c.path, path.display().to_string().as_bytes());
+        self.out.write_all(b" matches\n\n")
+    }
+
+    fn end(&mut self, _path: &Path, _stats: &FileStats) -> Result<()> {
+        self.out.write_all(b"\n")
+    }
+}
+
+fn paint<F>(out: &mut Vec<u8>, colors: Option<&Colors>, color: F, text: &[u8])
+where
+    F: Fn(&Colors) -> &str,
+{
+    match colors.map(color) {
+        Some(sgr) if !sgr.is_empty() => {
+            out.extend_from_slice(b"\x1b[");
+            out.extend_from_slice(sgr.as_bytes());
+            out.extend_from_slice(b"m");
+            out.extend_from_slice(text);
+            out.extend_from_slice(b"\x1b[0m");
+        }
+        _ => out.extend_from_slice(text),
+    }
+}
+
+/// One `path:line:column:text` line per match, which is what editors read as
+/// a quickfix list (`--vimgrep`). Columns are 1-based and count bytes.
+#[derive(Debug, Default)]
+pub struct VimgrepPrinter;
+
+impl Printer for VimgrepPrinter {
+    fn sink<'a>(&'a self, out: &'a mut Vec<u8>) -> Box<dyn Sink + 'a> {
+        Box::new(VimgrepSink { out })
+    }
+
+    fn unmatched(&self, _out: &mut Vec<u8>, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct VimgrepSink<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+impl Sink for VimgrepSink<'_> {
+    fn matched(&mut self, path: &Path, line: &Line) -> Result<bool> {
+        let text = String::from_utf8_lossy(line.bytes);
+        let text = text.trim_end_matches(['\r', '\n']);
+        for &(start, _) in &line.submatches {
+            writeln!(
+                self.out,
+                "{}:{}:{}:{}",
+                path.display(),
+                line.line_num,
+                start + 1,
+                text
+            )?;
+        }
+        Ok(true)
+    }
+}
+
+/// Prints `path:count` of matching lines for every file which matched
+/// (`-c/--count`).
+#[derive(Debug, Default)]
+pub struct CountPrinter;
+
+impl Printer for CountPrinter {
+    fn sink<'a>(&'a self, out: &'a mut Vec<u8>) -> Box<dyn Sink + 'a> {
+        Box::new(CountSink { out })
+    }
+
+    fn unmatched(&self, out: &mut Vec<u8>, path: &Path) -> Result<()> {
+        writeln!(out, "{}:0", path.display())
+    }
+}
+
+struct CountSink<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+impl Sink for CountSink<'_> {
+    fn matched(&mut self, _path: &Path, _line: &Line) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn binary_matched(&mut self, path: &Path, _stats: &FileStats) -> Result<()> {
+        // the lines of a binary file aren't counted, the search stops at the
+        // first match
+        writeln!(self.out, "Binary file {} matches", path.display())
+    }
+
+    fn end(&mut self, path: &Path, stats: &FileStats) -> Result<()> {
+        writeln!(self.out, "{}:{}", path.display(), stats.matched_lines)
+    }
+}
+
+/// Prints only the path of every file which matched, ended by newline or by
+/// NUL if `null` is set (`-l/--files-with-matches`). Each file is searched
+/// up to its first match.
+#[derive(Debug, Default)]
+pub struct FilesPrinter {
+    null: bool,
+}
+
+impl FilesPrinter {
+    pub fn new(null: bool) -> Self {
+        FilesPrinter { null }
+    }
+}
+
+impl Printer for FilesPrinter {
+    fn sink<'a>(&'a self, out: &'a mut Vec<u8>) -> Box<dyn Sink + 'a> {
+        Box::new(FilesSink {
+            null: self.null,
+            out,
+        })
+    }
+
+    fn unmatched(&self, _out: &mut Vec<u8>, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct FilesSink<'a> {
+    null: bool,
+    out: &'a mut Vec<u8>,
+}
+
+impl FilesSink<'_> {
+    fn path(&mut self, path: &Path) {
+        self.out
+            .extend_from_slice(path.as_os_str().as_encoded_bytes());
+        self.out.push(if self.null { b'\0' } else { b'\n' });
+    }
+}
+
+impl Sink for FilesSink<'_> {
+    fn matched(&mut self, path: &Path, _line: &Line) -> Result<bool> {
+        self.path(path);
+        Ok(false)
+    }
+
+    fn binary_matched(&mut self, path: &Path, _stats: &FileStats) -> Result<()> {
+        self.path(path);
+        Ok(())
+    }
+}
+
+/// JSON Lines, one object per event. Text that isn't valid UTF-8 is given
+/// as base64 encoded `bytes` instead of `text`.
+#[derive(Debug, Default)]
+pub struct JsonPrinter;
+
+impl JsonPrinter {
+    fn write_event(out: &mut dyn Write, kind: &str, data: Value) -> Result<()> {
+        serde_json::to_writer(&mut *out, &json!({ "type": kind, "data": data }))?;
+        out.write_all(b"\n")
+    }
+
+    fn line_event(out: &mut Vec<u8>, kind: &str, path: &Path, line: &Line) -> Result<()> {
+        let submatches: Vec<Value> = line
+            .submatches
+            .iter()
+            .enumerate()
+            .map(|(i, &(start, end))| {
+                let mut submatch = json!({
+                    "match": bytes_data(&line.bytes[start..end]),
+                    "start": start,
+                    "end": end,
+                });
+                if let Some(distance) = line.distances.get(i) {
+                    submatch["distance"] = json!(distance);
+                }
+                submatch
+            })
+            .collect();
+        JsonPrinter::write_event(
+            out,
+            kind,
+            json!({
+                "path": path_data(path),
+                "lines": bytes_data(line.bytes),
+                "line_number": line.line_num,
+                "absolute_offset": line.offset,
+                "submatches": submatches,
+            }),
+        )
+    }
+
+    fn end_event(out: &mut Vec<u8>, path: &Path, stats: &FileStats, binary: bool) -> Result<()> {
+        JsonPrinter::write_event(
+            out,
+            "end",
+            json!({
+                "path": path_data(path),
+                "binary": binary,
+                "stats": {
+                    "matched_lines": stats.matched_lines,
+                    "matches": stats.matches,
+                    "bytes_searched": stats.bytes_searched,
+                },
+            }),
+        )
+    }
+}
+
+impl Printer for JsonPrinter {
+    fn sink<'a>(&'a self, out: &'a mut Vec<u8>) -> Box<dyn Sink + 'a> {
+        Box::new(JsonSink { out })
+    }
+
+    fn unmatched(&self, out: &mut Vec<u8>, path: &Path) -> Result<()> {
+        JsonPrinter::write_event(out, "begin", json!({ "path": path_data(path) }))?;
+        JsonPrinter::end_event(out, path, &FileStats::default(), false)
+    }
+
+    fn summary(&self, out: &mut dyn Write, totals: &Totals, elapsed: Duration) -> Result<()> {
+        JsonPrinter::write_event(
+            out,
+            "summary",
+            json!({
+                "elapsed_total": {
+                    "secs": elapsed.as_secs(),
+                    "nanos": elapsed.subsec_nanos(),
+                    "human": format!("{:.6}s", elapsed.as_secs_f64()),
+                },
+                "stats": {
+                    "searched_files": totals.searched_files,
+                    "files_with_matches": totals.files_with_matches,
+                    "matched_lines": totals.matched_lines,
+                    "matches": totals.matches,
+                    "bytes_searched": totals.bytes_searched,
+                    "skipped": {
+                        "binary": totals.binary_files,
+                        "ignored": totals.ignored_files,
+                        "errors": totals.errored_files,
+                    },
+                },
+            }),
+        )
+    }
+}
+
+/// Writes the totals of a search in human readable form (`--stats`).
+pub fn write_stats(out: &mut dyn Write, totals: &Totals, elapsed: Duration) -> Result<()> {
+    let skipped = totals.binary_files + totals.ignored_files + totals.errored_files;
+    writeln!(out, "{} files searched", totals.searched_files)?;
+    writeln!(out, "{} files with matches", totals.files_with_matches)?;
+    writeln!(out, "{} matched lines", totals.matched_lines)?;
+    writeln!(out, "{} matches", totals.matches)?;
+    writeln!(out, "{} bytes searched", totals.bytes_searched)?;
+    writeln!(
+        out,
+        "{} files skipped ({} binary, {} ignored, {} errors)",
+        skipped, totals.binary_files, totals.ignored_files, totals.errored_files
+    )?;
+    writeln!(out, "{:.6} seconds elapsed", elapsed.as_secs_f64())
+}
+
+struct JsonSink<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+impl Sink for JsonSink<'_> {
+    fn begin(&mut self, path: &Path) -> Result<()> {
+        JsonPrinter::write_event(self.out, "begin", json!({ "path": path_data(path) }))
+    }
+
+    fn matched(&mut self, path: &Path, line: &Line) -> Result<bool> {
+        JsonPrinter::line_event(self.out, "match", path, line)?;
+        Ok(true)
+    }
+
+    fn context(&mut self, path: &Path, line: &Line) -> Result<bool> {
+        JsonPrinter::line_event(self.out, "context", path, line)?;
+        Ok(true)
+    }
+
+    fn binary_matched(&mut self, path: &Path, stats: &FileStats) -> Result<()> {
+        self.begin(path)?;
+        JsonPrinter::end_event(self.out, path, stats, true)
+    }
+
+    fn end(&mut self, path: &Path, stats: &FileStats) -> Result<()> {
+        JsonPrinter::end_event(self.out, path, stats, false)
+    }
+}
+
+fn bytes_data(bytes: &[u8]) -> Value {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => json!({ "text": text }),
+        Err(_) => json!({ "bytes": base64::encode(bytes) }),
+    }
+}
+
+fn path_data(path: &Path) -> Value {
+    match path.to_str() {
+        Some(text) => json!({ "text": text }),
+        #[cfg(unix)]
+        None => {
+            use std::os::unix::ffi::OsStrExt;
+            json!({ "bytes": base64::encode(path.as_os_str().as_bytes()) })
+        }
+        #[cfg(not(unix))]
+        None => json!({ "text": path.to_string_lossy() }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::matcher::{Matcher, MatcherOptions};
+    use crate::printer::*;
+    use crate::searcher::Searcher;
+
+    fn print(printer: &dyn Printer, contents: &str) -> String {
+        let matcher = Matcher::new(&["foo".to_string()], &MatcherOptions::default()).unwrap();
+        let mut out = Vec::new();
+        let mut sink = printer.sink(&mut out);
+        Searcher::new(matcher)
+            .search_slice(Path::new("a.txt"), contents.as_bytes(), &mut *sink)
+            .unwrap();
+        drop(sink);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn print_every_match_for_vim() {
+        let out = print(&VimgrepPrinter, "bar\nfoo foo\r\nbaz foo\n");
+        assert_eq!(
+            out,
+            "a.txt:2:1:foo foo\na.txt:2:5:foo foo\na.txt:3:5:baz foo\n"
+        );
+    }
+
+    #[test]
+    fn print_column_and_byte_offset() {
+        let printer = StandardPrinter::default().column(true).byte_offset(true);
+        let out = print(&printer, "bar\nbaz foo foo\n");
+        assert_eq!(
+            out,
+            "In file a.txt\nline 2, column 5, byte 4: baz foo foo\n\n"
+        );
+    }
+
+    #[test]
+    fn override_colors_from_spec() {
+        let colors = Colors::default().with_spec("ms=01;31:fn=35:sl=1:bogus:cx=2:mt=4");
+        assert_eq!(colors.matched, "4");
+        assert_eq!(colors.path, "35");
+        assert_eq!(colors.context, "2");
+        assert_eq!(colors.line_num, Colors::default().line_num);
+        assert_eq!(colors.separator, Colors::default().separator);
+    }
+}
@@ -0,0 +1,166 @@
+use similar::TextDiff;
+use tempfile::NamedTempFile;
+
+use crate::buffer::{Line, MatchingLines};
+use crate::matcher::Matcher;
+use crate::searcher::Sink;
+use crate::stats::FileStats;
+
+use std::fs;
+use std::io::{Result, Write};
+use std::path::Path;
+
+/// Returns `buf` with every match on a matching line replaced by `template`.
+pub fn replace_content(matcher: &Matcher, buf: &[u8], template: &[u8]) -> Vec<u8> {
+    let mut content = Vec::with_capacity(buf.len());
+    let mut copied = 0;
+    for line in MatchingLines::new(matcher, buf) {
+        content.extend_from_slice(&buf[copied..line.offset]);
+        content.extend_from_slice(&matcher.replace(line.bytes, template).0);
+        copied = line.offset + line.bytes.len();
+    }
+    content.extend_from_slice(&buf[copied..]);
+    content
+}
+
+/// Hands matched lines to `inner` with every match replaced by `template`,
+/// everything else is passed on as it is.
+pub struct ReplaceSink<'a, S> {
+    matcher: &'a Matcher,
+    template: &'a [u8],
+    inner: S,
+}
+
+impl<'a, S: Sink> ReplaceSink<'a, S> {
+    pub fn new(matcher: &'a Matcher, template: &'a [u8], inner: S) -> Self {
+        ReplaceSink {
+            matcher,
+            template,
+            inner,
+        }
+    }
+}
+
+impl<S: Sink> Sink for ReplaceSink<'_, S> {
+    fn begin(&mut self, path: &Path) -> Result<()> {
+        self.inner.begin(path)
+    }
+
+    fn matched(&mut self, path: &Path, line: &Line) -> Result<bool> {
+        let (bytes, submatches) = self.matcher.replace(line.bytes, self.template);
+        let line = Line {
+            bytes: &bytes,
+            submatches,
+            distances: line.distances.clone(),
+            ..*line
+        };
+        self.inner.matched(path, &line)
+    }
+
+    fn context(&mut self, path: &Path, line: &Line) -> Result<bool> {
+        self.inner.context(path, line)
+    }
+
+    fn context_break(&mut self) -> Result<()> {
+        self.inner.context_break()
+    }
+
+    fn binary_matched(&mut self, path: &Path, stats: &FileStats) -> Result<()> {
+        self.inner.binary_matched(path, stats)
+    }
+
+    fn end(&mut self, path: &Path, stats: &FileStats) -> Result<()> {
+        self.inner.end(path, stats)
+    }
+}
+
+/// Replaces the file at `path` with `content` by writing a temporary file
+/// next to it and renaming it over the original, so that readers never see a
+/// half written file. The permissions of the original are kept, and a symlink
+/// is left alone while the file it points to is replaced.
+pub fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+    let path = fs::canonicalize(path)?;
+    let permissions = fs::metadata(&path)?.permissions();
+    // a canonical path always has a parent, unless it's the root
+    let dir = path.parent().unwrap_or(Path::new("/"));
+
+    let mut tmp = NamedTempFile::new_in(dir)?;
+    tmp.write_all(content)?;
+    tmp.as_file().sync_all()?;
+    fs::set_permissions(tmp.path(), permissions)?;
+    tmp.persist(&path).map_err(|err| err.error)?;
+
+    Ok(())
+}
+
+/// Returns a unified diff between the old and new content of `path`.
+pub fn unified_diff(path: &Path, old: &[u8], new: &[u8]) -> String {
+    let old = String::from_utf8_lossy(old);
+    let new = String::from_utf8_lossy(new);
+    let name = path.display().to_string();
+    TextDiff::from_lines(old.as_ref(), new.as_ref())
+        .unified_diff()
+        .context_radius(3)
+        .header(&name, &name)
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::matcher::MatcherOptions;
+    use crate::replace::*;
+
+    #[test]
+    fn replace_matching_lines() {
+        let patterns = vec![r"(\w+)@example\.com".to_string()];
+        let matcher = Matcher::new(&patterns, &MatcherOptions::default()).unwrap();
+        let buf = b"to: a@example.com, b@example.com\nnone\r\ncc: c@example.com";
+        let content = replace_content(&matcher, buf, b"<$1>");
+        assert_eq!(content, b"to: <a>, <b>\nnone\r\ncc: <c>");
+
+        let diff = unified_diff(Path::new("mail.txt"), buf, &content);
+        assert!(diff.starts_with("--- mail.txt\n+++ mail.txt\n@@ -1,3 +1,3 @@\n"));
+        assert!(diff.contains("\n-to: a@example.com, b@example.com\n+to: <a>, <b>\n"));
+        assert!(diff.contains("\n none\r\n"));
+        assert_eq!(unified_diff(Path::new("mail.txt"), buf, buf), "");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_atomically_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("script.sh");
+        fs::write(&path, "echo hi\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).unwrap();
+
+        write_atomically(&path, b"echo bye\n").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"echo bye\n");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o750);
+        // the temporary file is gone
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_atomically_through_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("real/a.txt");
+        let link = dir.path().join("link.txt");
+        fs::create_dir(real.parent().unwrap()).unwrap();
+        fs::write(&real, "foo\n").unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        write_atomically(&link, b"bar\n").unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read(&real).unwrap(), b"bar\n");
+        // the temporary file went next to the real file and is gone
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+        assert_eq!(fs::read_dir(real.parent().unwrap()).unwrap().count(), 1);
+    }
+}
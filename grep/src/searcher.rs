@@ -0,0 +1,316 @@
+use crate::buffer::{Context, ContextItem, Line, MatchingLines, MultilineMatches};
+use crate::decode::{Decoded, Decoding};
+use crate::matcher::Matcher;
+use crate::stats::FileStats;
+
+use std::io::Result;
+use std::path::Path;
+
+/// Size of the leading block that is sniffed for NUL bytes.
+const BINARY_SNIFF_SIZE: usize = 8 * 1024;
+
+/// How to treat files that look binary, like GNU grep's `--binary-files`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryFiles {
+    /// Report "Binary file X matches" instead of the matched lines.
+    Binary,
+    /// Search and print the file as if it were text.
+    Text,
+    /// Assume the file doesn't match.
+    WithoutMatch,
+}
+
+/// Receives what a `Searcher` finds in one buffer.
+///
+/// `begin` and `end` frame the lines of a text buffer, even if nothing
+/// matched. A binary buffer which matched only gets `binary_matched`.
+/// Returning `false` from `matched` or `context` stops the search early.
+pub trait Sink {
+    fn begin(&mut self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn matched(&mut self, path: &Path, line: &Line) -> Result<bool>;
+
+    fn context(&mut self, _path: &Path, _line: &Line) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Called between groups of lines which aren't adjacent.
+    fn context_break(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn binary_matched(&mut self, _path: &Path, _stats: &FileStats) -> Result<()> {
+        Ok(())
+    }
+
+    fn end(&mut self, _path: &Path, _stats: &FileStats) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: Sink + ?Sized> Sink for &mut S {
+    fn begin(&mut self, path: &Path) -> Result<()> {
+        (**self).begin(path)
+    }
+
+    fn matched(&mut self, path: &Path, line: &Line) -> Result<bool> {
+        (**self).matched(path, line)
+    }
+
+    fn context(&mut self, path: &Path, line: &Line) -> Result<bool> {
+        (**self).context(path, line)
+    }
+
+    fn context_break(&mut self) -> Result<()> {
+        (**self).context_break()
+    }
+
+    fn binary_matched(&mut self, path: &Path, stats: &FileStats) -> Result<()> {
+        (**self).binary_matched(path, stats)
+    }
+
+    fn end(&mut self, path: &Path, stats: &FileStats) -> Result<()> {
+        (**self).end(path, stats)
+    }
+}
+
+/// Finds the matching lines of a buffer, along with their context, and hands
+/// them to a `Sink`.
+#[derive(Debug, Clone)]
+pub struct Searcher {
+    pub matcher: Matcher,
+    /// Let matches span lines, see `MultilineMatches`.
+    pub multiline: bool,
+    pub before_context: usize,
+    pub after_context: usize,
+    pub binary_files: BinaryFiles,
+    pub decoding: Decoding,
+}
+
+impl Searcher {
+    pub fn new(matcher: Matcher) -> Searcher {
+        Searcher {
+            matcher,
+            multiline: false,
+            before_context: 0,
+            after_context: 0,
+            binary_files: BinaryFiles::Binary,
+            decoding: Decoding::Auto,
+        }
+    }
+
+    pub fn is_binary(&self, contents: &[u8]) -> bool {
+        let sniff_len = contents.len().min(BINARY_SNIFF_SIZE);
+        self.binary_files != BinaryFiles::Text && contents[..sniff_len].contains(&0)
+    }
+
+    /// Searches `contents`, which `path` is only used to report. Offsets
+    /// passed to `sink` are always offsets into `contents`, even if they had
+    /// to be decoded.
+    pub fn search_slice<S: Sink>(
+        &self,
+        path: &Path,
+        contents: &[u8],
+        sink: S,
+    ) -> Result<FileStats> {
+        match self.decoding.encoding_of(contents) {
+            Some(encoding) => {
+                let decoded = Decoded::new(contents, encoding);
+                self.search_text(path, &decoded.text, Some(&decoded), sink)
+            }
+            None => self.search_text(path, contents, None, sink),
+        }
+    }
+
+    fn search_text<S: Sink>(
+        &self,
+        path: &Path,
+        text: &[u8],
+        decoded: Option<&Decoded>,
+        mut sink: S,
+    ) -> Result<FileStats> {
+        let mut lines: Box<dyn Iterator<Item = Line>> = if self.multiline {
+            Box::new(MultilineMatches::new(&self.matcher, text))
+        } else {
+            Box::new(MatchingLines::new(&self.matcher, text))
+        };
+        let mut stats = FileStats {
+            bytes_searched: text.len() as u64,
+            ..Default::default()
+        };
+
+        if self.is_binary(text) {
+            if self.binary_files == BinaryFiles::WithoutMatch {
+                return Ok(FileStats {
+                    skipped_binary: true,
+                    ..Default::default()
+                });
+            }
+            stats.matched = lines.next().is_some();
+            if stats.matched {
+                sink.binary_matched(path, &stats)?;
+            }
+            return Ok(stats);
+        }
+
+        let mut context = Context::new(text, self.before_context, self.after_context);
+        sink.begin(path)?;
+        let mut stopped = false;
+        'lines: for line in lines {
+            for item in context.before_match(&line) {
+                if !send_context(&mut sink, path, item, decoded)? {
+                    stopped = true;
+                    break 'lines;
+                }
+            }
+            stats.matched_lines += 1;
+            // a match over several lines is counted on the line it starts on
+            stats.matches += (line.submatches.len() - line.continued as usize) as u64;
+            if !sink.matched(path, &locate(line, decoded))? {
+                stopped = true;
+                break;
+            }
+        }
+        if !stopped {
+            for item in context.finish() {
+                if !send_context(&mut sink, path, item, decoded)? {
+                    break;
+                }
+            }
+        }
+        stats.matched = stats.matched_lines > 0;
+        sink.end(path, &stats)?;
+
+        Ok(stats)
+    }
+}
+
+fn send_context<S: Sink>(
+    sink: &mut S,
+    path: &Path,
+    item: ContextItem,
+    decoded: Option<&Decoded>,
+) -> Result<bool> {
+    match item {
+        ContextItem::Line(line) => sink.context(path, &locate(line, decoded)),
+        ContextItem::Break => sink.context_break().map(|_| true),
+    }
+}
+
+/// Gives `line` the offset it has in the original buffer rather than in the
+/// decoded text.
+fn locate<'a>(line: Line<'a>, decoded: Option<&Decoded>) -> Line<'a> {
+    match decoded {
+        Some(decoded) => Line {
+            offset: decoded.original_offset(line.line_num, line.offset),
+            ..line
+        },
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::matcher::MatcherOptions;
+    use crate::searcher::*;
+    use crate::stats::Stats;
+
+    /// Collects the numbers of the matched lines, and whether a binary file
+    /// was reported to match.
+    #[derive(Default)]
+    struct Found {
+        lines: Vec<usize>,
+        binary: bool,
+    }
+
+    impl Sink for Found {
+        fn matched(&mut self, _path: &Path, line: &Line) -> Result<bool> {
+            self.lines.push(line.line_num);
+            Ok(true)
+        }
+
+        fn binary_matched(&mut self, _path: &Path, _stats: &FileStats) -> Result<()> {
+            self.binary = true;
+            Ok(())
+        }
+    }
+
+    fn searcher(pattern: &str, multiline: bool) -> Searcher {
+        let options = MatcherOptions {
+            multiline,
+            ..Default::default()
+        };
+        let mut searcher = Searcher::new(Matcher::new(&[pattern.to_string()], &options).unwrap());
+        searcher.multiline = multiline;
+        searcher
+    }
+
+    #[test]
+    fn count_multiline_matches_once() {
+        let buf = b"a x)\n# b x)\n# c\n";
+        let mut found = Found::default();
+        let stats = searcher(r"x\)\n#", true)
+            .search_slice(Path::new("a.txt"), buf, &mut found)
+            .unwrap();
+        assert_eq!(found.lines, [1, 2, 3]);
+        assert_eq!(stats.matched_lines, 3);
+        assert_eq!(stats.matches, 2);
+    }
+
+    #[test]
+    fn sniff_binary_files() {
+        let mut searcher = searcher("foo", false);
+        assert!(searcher.is_binary(b"foo\0bar"));
+        assert!(!searcher.is_binary(b"foo bar\n"));
+        assert!(!searcher.is_binary(b""));
+        // only the first block is sniffed
+        let mut late_nul = vec![b'a'; BINARY_SNIFF_SIZE];
+        late_nul.push(0);
+        assert!(!searcher.is_binary(&late_nul));
+
+        searcher.binary_files = BinaryFiles::Text;
+        assert!(!searcher.is_binary(b"foo\0bar"));
+    }
+
+    #[test]
+    fn report_or_skip_binary_files() {
+        let buf = b"foo\0\nfoo\n";
+        let mut searcher = searcher("foo", false);
+
+        let mut found = Found::default();
+        let stats = searcher
+            .search_slice(Path::new("a.bin"), buf, &mut found)
+            .unwrap();
+        assert!(found.binary && found.lines.is_empty());
+        assert!(stats.matched && !stats.skipped_binary);
+        assert_eq!(stats.bytes_searched, buf.len() as u64);
+
+        searcher.binary_files = BinaryFiles::WithoutMatch;
+        let mut found = Found::default();
+        let skipped = searcher
+            .search_slice(Path::new("a.bin"), buf, &mut found)
+            .unwrap();
+        assert!(!found.binary && found.lines.is_empty());
+        assert!(!skipped.matched && skipped.skipped_binary);
+        assert_eq!(skipped.bytes_searched, 0);
+
+        // a skipped file counts as skipped rather than searched
+        let totals = Stats::default();
+        totals.add_file(&stats);
+        totals.add_file(&skipped);
+        let totals = totals.totals();
+        assert_eq!(totals.searched_files, 1);
+        assert_eq!(totals.files_with_matches, 1);
+        assert_eq!(totals.binary_files, 1);
+
+        searcher.binary_files = BinaryFiles::Text;
+        let mut found = Found::default();
+        searcher
+            .search_slice(Path::new("a.bin"), buf, &mut found)
+            .unwrap();
+        assert!(!found.binary);
+        assert_eq!(found.lines, [1, 2]);
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts gathered while searching a single file.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FileStats {
+    /// Whether anything matched, which for a binary file doesn't show in the
+    /// counts of lines.
+    pub matched: bool,
+    /// The file looked binary and wasn't searched for it
+    /// (`--binary-files=without-match`), every other count is zero.
+    pub skipped_binary: bool,
+    pub matched_lines: u64,
+    pub matches: u64,
+    pub bytes_searched: u64,
+}
+
+/// Totals over all searched files.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Totals {
+    pub searched_files: u64,
+    pub files_with_matches: u64,
+    pub matched_lines: u64,
+    pub matches: u64,
+    pub bytes_searched: u64,
+    /// Files which weren't searched for looking binary.
+    pub binary_files: u64,
+    /// Files or directories which were hidden, or which ignore files, the walk
+    /// filters or the index ruled out. A directory counts once.
+    pub ignored_files: u64,
+    /// Files which couldn't be searched.
+    pub errored_files: u64,
+}
+
+/// Totals which the walker threads and the rayon workers add to concurrently.
+#[derive(Debug, Default)]
+pub struct Stats {
+    searched_files: AtomicU64,
+    files_with_matches: AtomicU64,
+    matched_lines: AtomicU64,
+    matches: AtomicU64,
+    bytes_searched: AtomicU64,
+    binary_files: AtomicU64,
+    ignored_files: AtomicU64,
+    errored_files: AtomicU64,
+}
+
+impl Stats {
+    pub fn add_file(&self, stats: &FileStats) {
+        if stats.skipped_binary {
+            self.binary_files.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.searched_files.fetch_add(1, Ordering::Relaxed);
+        if stats.matched {
+            self.files_with_matches.fetch_add(1, Ordering::Relaxed);
+        }
+        self.matched_lines
+            .fetch_add(stats.matched_lines, Ordering::Relaxed);
+        self.matches.fetch_add(stats.matches, Ordering::Relaxed);
+        self.bytes_searched
+            .fetch_add(stats.bytes_searched, Ordering::Relaxed);
+    }
+
+    pub fn add_ignored(&self, files: u64) {
+        self.ignored_files.fetch_add(files, Ordering::Relaxed);
+    }
+
+    pub fn add_error(&self) {
+        self.errored_files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn totals(&self) -> Totals {
+        Totals {
+            searched_files: self.searched_files.load(Ordering::Relaxed),
+            files_with_matches: self.files_with_matches.load(Ordering::Relaxed),
+            matched_lines: self.matched_lines.load(Ordering::Relaxed),
+            matches: self.matches.load(Ordering::Relaxed),
+            bytes_searched: self.bytes_searched.load(Ordering::Relaxed),
+            binary_files: self.binary_files.load(Ordering::Relaxed),
+            ignored_files: self.ignored_files.load(Ordering::Relaxed),
+            errored_files: self.errored_files.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,478 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::types::{Types, TypesBuilder};
+use ignore::{DirEntry, WalkBuilder};
+
+use std::ffi::OsStr;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Decides which directory entries the walker descends into or yields.
+///
+/// Globs are matched against the base name of an entry, like GNU grep's
+/// `--include`, `--exclude` and `--exclude-dir`.
+#[derive(Debug, Clone, Default)]
+pub struct GlobFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    exclude_dir: Option<GlobSet>,
+}
+
+impl GlobFilter {
+    pub fn new(
+        include: &[&str],
+        exclude: &[&str],
+        exclude_dir: &[&str],
+    ) -> Result<GlobFilter, globset::Error> {
+        Ok(GlobFilter {
+            include: build_glob_set(include)?,
+            exclude: build_glob_set(exclude)?,
+            exclude_dir: build_glob_set(exclude_dir)?,
+        })
+    }
+
+    pub fn is_file_allowed(&self, name: &OsStr) -> bool {
+        if let Some(ref exclude) = self.exclude {
+            if exclude.is_match(name) {
+                return false;
+            }
+        }
+        match self.include {
+            Some(ref include) => include.is_match(name),
+            None => true,
+        }
+    }
+
+    pub fn is_dir_allowed(&self, name: &OsStr) -> bool {
+        match self.exclude_dir {
+            Some(ref exclude_dir) => !exclude_dir.is_match(name),
+            None => true,
+        }
+    }
+}
+
+fn build_glob_set(globs: &[&str]) -> Result<Option<GlobSet>, globset::Error> {
+    if globs.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob)?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Skips files by their size, modification time or owner, going by the
+/// metadata the walker already fetched.
+#[derive(Debug, Clone, Default)]
+pub struct MetadataFilter {
+    pub max_filesize: Option<u64>,
+    /// Only files modified after this time are searched.
+    pub newer_than: Option<SystemTime>,
+    /// Only files modified before this time are searched.
+    pub older_than: Option<SystemTime>,
+    /// Only files owned by this user id are searched, Unix only.
+    pub owner: Option<u32>,
+}
+
+impl MetadataFilter {
+    pub fn is_allowed(&self, meta: &Metadata) -> bool {
+        if self.max_filesize.is_some_and(|max| meta.len() > max) {
+            return false;
+        }
+        if self.newer_than.is_some() || self.older_than.is_some() {
+            let modified = match meta.modified() {
+                Ok(modified) => modified,
+                Err(_) => return false,
+            };
+            if self.newer_than.is_some_and(|time| modified <= time)
+                || self.older_than.is_some_and(|time| modified >= time)
+            {
+                return false;
+            }
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            if self.owner.is_some_and(|uid| meta.uid() != uid) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Parses a size like `512`, `10K`, `10M` or `1G`, in powers of 1024.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let (number, unit) = match size.as_bytes().last()? {
+        b'K' | b'k' => (&size[..size.len() - 1], 1 << 10),
+        b'M' | b'm' => (&size[..size.len() - 1], 1 << 20),
+        b'G' | b'g' => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// Parses either an age like `30s`, `15m`, `3h`, `2d` or `1w` before `now`,
+/// or a UTC date like `2024-01-31` or `2024-01-31T12:00:00`.
+pub fn parse_time(time: &str, now: SystemTime) -> Option<SystemTime> {
+    let unit = match time.as_bytes().last()? {
+        b's' => Some(1),
+        b'm' => Some(60),
+        b'h' => Some(60 * 60),
+        b'd' => Some(24 * 60 * 60),
+        b'w' => Some(7 * 24 * 60 * 60),
+        _ => None,
+    };
+    if let Some(unit) = unit {
+        let number: u64 = time[..time.len() - 1].parse().ok()?;
+        return now.checked_sub(Duration::from_secs(number.checked_mul(unit)?));
+    }
+
+    let (date, clock) = time.split_once('T').unwrap_or((time, "00:00:00"));
+    let date: Vec<i64> = date
+        .split('-')
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    let clock: Vec<i64> = clock
+        .split(':')
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    let (year, month, day) = match date[..] {
+        [year, month @ 1..=12, day @ 1..=31] => (year, month, day),
+        _ => return None,
+    };
+    let (hour, minute, second) = match clock[..] {
+        [hour @ 0..=23, minute @ 0..=59] => (hour, minute, 0),
+        [hour @ 0..=23, minute @ 0..=59, second @ 0..=59] => (hour, minute, second),
+        _ => return None,
+    };
+    let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    if secs >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))
+    }
+}
+
+/// Days between 1970-01-01 and a date of the proleptic Gregorian calendar,
+/// after Howard Hinnant's `days_from_civil`.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Returns the id of `user`, a name from `/etc/passwd` or a number.
+#[cfg(unix)]
+pub fn user_id(user: &str) -> Option<u32> {
+    if let Ok(uid) = user.parse() {
+        return Some(uid);
+    }
+    let passwd = std::fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|entry| {
+        let mut fields = entry.split(':');
+        if fields.next()? != user {
+            return None;
+        }
+        fields.nth(1)?.parse().ok()
+    })
+}
+
+#[cfg(not(unix))]
+pub fn user_id(_user: &str) -> Option<u32> {
+    None
+}
+
+/// Returns the default file type table, extended with `--type-add`
+/// definitions of the form `name:glob`.
+pub fn types_builder(definitions: &[&str]) -> Result<TypesBuilder, ignore::Error> {
+    let mut builder = TypesBuilder::new();
+    builder.add_defaults();
+    for def in definitions {
+        builder.add_def(def)?;
+    }
+    Ok(builder)
+}
+
+/// Builds the matcher for `-t` and `-T`, or `None` if no type was given.
+pub fn file_types(
+    definitions: &[&str],
+    select: &[&str],
+    negate: &[&str],
+) -> Result<Option<Types>, ignore::Error> {
+    if select.is_empty() && negate.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = types_builder(definitions)?;
+    for name in select {
+        builder.select(name);
+    }
+    for name in negate {
+        builder.negate(name);
+    }
+    Ok(Some(builder.build()?))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WalkConfig {
+    pub hidden: bool,
+    pub no_ignore: bool,
+    pub follow_links: bool,
+    pub one_file_system: bool,
+    pub max_depth: Option<usize>,
+    pub filter: GlobFilter,
+    pub types: Option<Types>,
+    pub metadata: MetadataFilter,
+}
+
+impl WalkConfig {
+    /// Creates a walker over `dir` which honours `.gitignore`, `.ignore` and
+    /// the global git excludes, and skips hidden entries unless asked not to.
+    /// When following symlinks, a link back to one of its ancestors is
+    /// yielded as a loop error instead of being descended into.
+    pub fn walker(&self, dir: &str, max_depth: Option<usize>) -> WalkBuilder {
+        let mut builder = WalkBuilder::new(dir);
+        builder
+            .hidden(!self.hidden)
+            .parents(!self.no_ignore)
+            .ignore(!self.no_ignore)
+            .git_ignore(!self.no_ignore)
+            .git_global(!self.no_ignore)
+            .git_exclude(!self.no_ignore)
+            .require_git(false)
+            .follow_links(self.follow_links)
+            .same_file_system(self.one_file_system)
+            .max_depth(max_depth);
+        if let Some(ref types) = self.types {
+            builder.types(types.clone());
+        }
+
+        let filter = self.filter.clone();
+        builder.filter_entry(move |entry| {
+            // never filter out the directory we were asked to search
+            if entry.depth() == 0 {
+                return true;
+            }
+            match entry.file_type() {
+                Some(t) if t.is_dir() => filter.is_dir_allowed(entry.file_name()),
+                Some(t) if t.is_file() => filter.is_file_allowed(entry.file_name()),
+                _ => true,
+            }
+        });
+
+        builder
+    }
+
+    /// Tells whether a walk of its directory would yield `path`, a file or
+    /// directory which showed up after the walk, going by the same ignore
+    /// files, globs and types.
+    pub fn accepts(&self, path: &Path) -> bool {
+        let (dir, name) = match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => return false,
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        self.walker(&dir.to_string_lossy(), Some(1))
+            .build()
+            .filter_map(Result::ok)
+            .any(|entry| entry.depth() == 1 && entry.file_name() == name)
+    }
+}
+
+/// Returns the link which leads back to one of its ancestors if `err` is
+/// about a symlink loop, which walks only run into when following links.
+pub fn loop_link(err: &ignore::Error) -> Option<&Path> {
+    match err {
+        ignore::Error::Loop { child, .. } => Some(child),
+        ignore::Error::WithPath { err, .. } | ignore::Error::WithDepth { err, .. } => {
+            loop_link(err)
+        }
+        _ => None,
+    }
+}
+
+/// Counts the entries a walk skipped, for being hidden or because ignore
+/// files, globs or types ruled them out, by comparing what the directories the
+/// walker went into hold with what it yielded. A skipped directory counts as
+/// one entry, it's never walked to find out what's in it.
+#[derive(Debug, Default)]
+pub struct SkipCounter {
+    listed: AtomicU64,
+    yielded: AtomicU64,
+}
+
+impl SkipCounter {
+    /// Records an entry the walker yielded in a walk going `max_depth` levels
+    /// deep.
+    pub fn walked(&self, entry: &DirEntry, max_depth: Option<usize>) {
+        if entry.depth() > 0 {
+            self.yielded.fetch_add(1, Ordering::Relaxed);
+        }
+        let descended = entry.file_type().is_some_and(|t| t.is_dir())
+            && max_depth.is_none_or(|max_depth| entry.depth() < max_depth);
+        if descended {
+            // the walker reports the directories it can't read
+            if let Ok(children) = fs::read_dir(entry.path()) {
+                let listed = children.count() as u64;
+                self.listed.fetch_add(listed, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn skipped(&self) -> u64 {
+        let listed = self.listed.load(Ordering::Relaxed);
+        listed.saturating_sub(self.yielded.load(Ordering::Relaxed))
+    }
+}
+
+/// Splits a list of paths separated by NUL, or by newline if there's no NUL
+/// in it, like the output of `find -print0` or `find`. The bytes of a path are
+/// kept as they are on unix, whatever their encoding.
+pub fn split_path_list(content: &[u8]) -> Vec<PathBuf> {
+    let separator = if content.contains(&b'\0') {
+        b'\0'
+    } else {
+        b'\n'
+    };
+
+    content
+        .split(|&b| b == separator)
+        .map(|path| path.strip_suffix(b"\r").unwrap_or(path))
+        .filter(|path| !path.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::walk::*;
+
+    use std::fs;
+
+    #[test]
+    fn count_skipped_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "a.rs",
+            "b.rs",
+            "c.txt",
+            "vendor/d.rs",
+            ".cache/f.rs",
+            ".gitignore",
+            "target/e.rs",
+        ] {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "x").unwrap();
+        }
+        fs::write(dir.path().join(".gitignore"), "target/\n").unwrap();
+
+        let config = WalkConfig {
+            filter: GlobFilter::new(&[], &["b.*"], &["vendor"]).unwrap(),
+            types: file_types(&[], &["rust"], &[]).unwrap(),
+            ..Default::default()
+        };
+        let root = dir.path().to_str().unwrap();
+        let skips = SkipCounter::default();
+        let mut files: Vec<_> = config
+            .walker(root, None)
+            .build()
+            .filter_map(Result::ok)
+            .inspect(|entry| skips.walked(entry, None))
+            .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+            .map(|entry| entry.file_name().to_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["a.rs"]);
+        // b.rs by the glob, c.txt by its type, vendor by the directory glob,
+        // .cache and .gitignore for being hidden and target by .gitignore
+        assert_eq!(skips.skipped(), 6);
+    }
+
+    #[test]
+    fn parse_filter_values() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("10M"), Some(10 << 20));
+        assert_eq!(parse_size("10X"), None);
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let secs = |time| {
+            parse_time(time, now)
+                .map(|t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap().as_secs())
+        };
+        assert_eq!(secs("2d"), Some(1_000_000 - 2 * 86400));
+        assert_eq!(secs("2024-02-29"), Some(1_709_164_800));
+        assert_eq!(secs("2024-02-29T12:30"), Some(1_709_164_800 + 45_000));
+        assert_eq!(secs("2024-13-01"), None);
+        assert_eq!(secs("yesterday"), None);
+    }
+
+    #[test]
+    fn split_path_lists() {
+        assert_eq!(
+            split_path_list(b"a.txt\r\nb c.txt\n\nd.txt"),
+            [Path::new("a.txt"), Path::new("b c.txt"), Path::new("d.txt")]
+        );
+        // newlines are part of the names once there's a NUL
+        assert_eq!(
+            split_path_list(b"a\nb.txt\0c.txt\0"),
+            [Path::new("a\nb.txt"), Path::new("c.txt")]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keep_non_utf8_paths() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let paths = split_path_list(b"caf\xe9.txt\0");
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].as_os_str().as_bytes(), b"caf\xe9.txt");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn report_symlink_loops() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/a.txt"), "x").unwrap();
+        std::os::unix::fs::symlink("..", dir.path().join("sub/back")).unwrap();
+
+        let config = WalkConfig {
+            follow_links: true,
+            ..Default::default()
+        };
+        let (mut files, mut loops) = (Vec::new(), Vec::new());
+        for result in config.walker(dir.path().to_str().unwrap(), None).build() {
+            match result {
+                Ok(entry) if entry.file_type().is_some_and(|t| t.is_file()) => {
+                    files.push(entry.into_path())
+                }
+                Ok(_) => {}
+                Err(err) => loops.push(loop_link(&err).map(Path::to_path_buf)),
+            }
+        }
+        assert_eq!(files, [dir.path().join("sub/a.txt")]);
+        assert_eq!(loops, [Some(dir.path().join("sub/back"))]);
+    }
+}
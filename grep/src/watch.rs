@@ -0,0 +1,218 @@
+use crossbeam::channel::{self, Receiver};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Result};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long to wait for more events after one arrived, since saving a file
+/// usually takes several writes or a write and a rename.
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+/// The files and directories a search went through, which is what `--watch`
+/// keeps an eye on afterwards.
+///
+/// Events come with canonical paths, so everything is keyed by the canonical
+/// path of its directory. Files are left as they are, since they may be gone
+/// by the time an event about them arrives.
+///
+/// New files are only picked up in walked directories, a file which was given
+/// by name is watched on its own.
+#[derive(Debug, Default)]
+pub struct Watched {
+    /// The walked directories, with the path they were walked as and how many
+    /// levels below them were walked, `None` for all of them.
+    dirs: HashMap<PathBuf, (PathBuf, Option<usize>)>,
+    /// The searched files, with the path they were first searched as.
+    files: HashMap<PathBuf, PathBuf>,
+    matched: HashSet<PathBuf>,
+    canonical_dirs: HashMap<PathBuf, PathBuf>,
+}
+
+impl Watched {
+    /// Records a walked directory whose files are searched, and below which
+    /// `levels` levels are walked. A directory walked twice keeps the deeper
+    /// walk.
+    pub fn add_dir(&mut self, dir: &Path, levels: Option<usize>) {
+        let key = self.canonical_dir(dir);
+        let walked = self
+            .dirs
+            .entry(key)
+            .or_insert_with(|| (dir.to_path_buf(), levels));
+        walked.1 = match (walked.1, levels) {
+            (Some(old), Some(new)) => Some(old.max(new)),
+            _ => None,
+        };
+    }
+
+    /// Tells whether `dir` was walked already.
+    pub fn is_walked(&mut self, dir: &Path) -> bool {
+        let key = self.canonical_dir(dir);
+        self.dirs.contains_key(&key)
+    }
+
+    /// Records that `path` was searched, and returns whether it matched the
+    /// previous time.
+    pub fn searched(&mut self, path: &Path, matched: bool) -> bool {
+        let key = self.key(path);
+        self.files
+            .entry(key.clone())
+            .or_insert_with(|| path.to_path_buf());
+        if matched {
+            !self.matched.insert(key)
+        } else {
+            self.matched.remove(&key)
+        }
+    }
+
+    /// Returns the path `path` was searched as, if it was.
+    pub fn searched_as(&mut self, path: &Path) -> Option<PathBuf> {
+        let key = self.key(path);
+        self.files.get(&key).cloned()
+    }
+
+    /// Returns the path `path`, which wasn't searched, would have been walked
+    /// as if it's in one of the walked directories, along with how many levels
+    /// below its directory were walked.
+    pub fn walked_as(&mut self, path: &Path) -> Option<(PathBuf, Option<usize>)> {
+        let dir = self.canonical_dir(path.parent()?);
+        let (walked, levels) = self.dirs.get(&dir)?;
+        Some((walked.join(path.file_name()?), *levels))
+    }
+
+    /// Returns the directories to watch: the walked ones, and those of the
+    /// files which were given by name.
+    pub fn dirs_to_watch(&self) -> HashSet<PathBuf> {
+        let file_dirs = self.files.keys().filter_map(|key| key.parent());
+        self.dirs
+            .keys()
+            .map(PathBuf::as_path)
+            .chain(file_dirs)
+            .map(Path::to_path_buf)
+            .collect()
+    }
+
+    /// Removes `path` from the files which matched, and returns whether it
+    /// was one of them.
+    pub fn forget_match(&mut self, path: &Path) -> bool {
+        let key = self.key(path);
+        self.matched.remove(&key)
+    }
+
+    fn key(&mut self, path: &Path) -> PathBuf {
+        match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) => self.canonical_dir(dir).join(name),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    fn canonical_dir(&mut self, dir: &Path) -> PathBuf {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        self.canonical_dirs
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf()))
+            .clone()
+    }
+}
+
+/// Watches directories, without descending into their subdirectories, and
+/// reports the paths which changed in them.
+pub struct Changes {
+    // dropping the watcher stops the events
+    watcher: RecommendedWatcher,
+    watching: HashSet<PathBuf>,
+    events: Receiver<notify::Result<Event>>,
+}
+
+impl Changes {
+    pub fn watch(watched: &Watched) -> Result<Changes> {
+        let (sender, events) = channel::unbounded();
+        let watcher = notify::recommended_watcher(sender).map_err(io::Error::other)?;
+        let mut changes = Changes {
+            watcher,
+            watching: HashSet::new(),
+            events,
+        };
+        changes.update(watched)?;
+
+        Ok(changes)
+    }
+
+    /// Starts watching the directories which were added to `watched` since.
+    pub fn update(&mut self, watched: &Watched) -> Result<()> {
+        for dir in watched.dirs_to_watch() {
+            if !self.watching.contains(&dir) {
+                self.watcher
+                    .watch(&dir, RecursiveMode::NonRecursive)
+                    .map_err(io::Error::other)?;
+                self.watching.insert(dir);
+            }
+        }
+        Ok(())
+    }
+
+    /// Blocks until something changes and returns every path which changed
+    /// until things settle down again.
+    pub fn next_batch(&self) -> Result<BTreeSet<PathBuf>> {
+        let mut changed = BTreeSet::new();
+        let event = self.events.recv().map_err(io::Error::other)?;
+        add_paths(&mut changed, event)?;
+        while let Ok(event) = self.events.recv_timeout(SETTLE_TIME) {
+            add_paths(&mut changed, event)?;
+        }
+
+        Ok(changed)
+    }
+}
+
+fn add_paths(changed: &mut BTreeSet<PathBuf>, event: notify::Result<Event>) -> Result<()> {
+    let event = event.map_err(io::Error::other)?;
+    if !matches!(event.kind, EventKind::Access(_)) {
+        changed.extend(event.paths);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::watch::*;
+
+    #[test]
+    fn pick_up_new_files_in_walked_dirs_only() {
+        let root = tempfile::tempdir().unwrap();
+        let walked = root.path().join("walked");
+        let given = root.path().join("given");
+        fs::create_dir_all(walked.join("sub")).unwrap();
+        fs::create_dir(&given).unwrap();
+        fs::write(given.join("a"), "x").unwrap();
+        // events come with canonical paths
+        let canonical = fs::canonicalize(root.path()).unwrap();
+
+        let mut watched = Watched::default();
+        watched.add_dir(&walked, Some(2));
+        assert!(!watched.searched(&given.join("a"), true));
+        assert!(watched.searched(&given.join("a"), false));
+
+        assert_eq!(
+            watched.walked_as(&canonical.join("walked/new")),
+            Some((walked.join("new"), Some(2)))
+        );
+        assert_eq!(watched.walked_as(&canonical.join("given/other")), None);
+        assert_eq!(
+            watched.searched_as(&canonical.join("given/a")),
+            Some(given.join("a"))
+        );
+        assert!(watched.is_walked(&canonical.join("walked")));
+        assert!(!watched.is_walked(&canonical.join("walked/sub")));
+
+        let dirs = watched.dirs_to_watch();
+        assert_eq!(dirs.len(), 2);
+        assert!(dirs.contains(&canonical.join("given")));
+    }
+}
@@ -0,0 +1,50 @@
+//! The exit status is only decided by the binary, so these run it.
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn grep(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_grep"))
+        .args(args)
+        .current_dir(dir)
+        // keep the config file of whoever runs the tests out of it
+        .env("GREP_CONFIG_PATH", "")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn keep_searching_after_a_path_error() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("a.txt"), "foo\n").unwrap();
+
+    let output = grep(
+        dir.path(),
+        &["foo", "--color=never", "-f", "missing.txt", "a.txt"],
+    );
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(output.stdout, b"In file a.txt\nline 1: foo\n\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("grep: missing.txt: "), "{}", stderr);
+
+    // -s only silences the messages
+    let output = grep(dir.path(), &["foo", "-s", "-f", "missing.txt", "a.txt"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stderr.is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn keep_walking_after_a_path_error() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("sub")).unwrap();
+    fs::write(dir.path().join("sub/a.txt"), "foo\n").unwrap();
+    std::os::unix::fs::symlink("gone.txt", dir.path().join("sub/dangling.txt")).unwrap();
+
+    let output = grep(dir.path(), &["foo", "-L", "-r", "-d", "sub", "--count"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(output.stdout, b"sub/a.txt:1\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("dangling.txt"), "{}", stderr);
+}